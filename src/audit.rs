@@ -0,0 +1,195 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::{Lazy, Mutex};
+
+use crate::memory::accounting::{self, Tag};
+use crate::platform::time::timestamp;
+use crate::prelude::*;
+use crate::wasm::SipId;
+
+const AUDIT_LOG_SIZE: usize = 256;
+
+pub static AUDIT_LOG: AuditLog = AuditLog::new();
+
+/// Security relevant events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    CapabilityGranted,
+    CapabilityDenied,
+    /// A capability granted to the SIP was taken back when it exited
+    CapabilityRevoked,
+    ModuleLoaded,
+    /// The module failed to parse or instantiate
+    ModuleRejected,
+    /// The module's signature failed verification
+    SignatureFailure,
+    /// An import has a different signature than the host function it names
+    ImportMismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    /// Position of this entry in the log, keeps counting after old entries are
+    /// discarded
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub sip_id: SipId,
+    pub event: AuditEvent,
+    /// The capability or module involved
+    pub object: String,
+}
+
+/// Bounded log of security relevant events, kept apart from the kernel log so
+/// regular logging can't push them out
+pub struct AuditLog {
+    entries: Lazy<Mutex<VecDeque<AuditEntry>>>,
+    next_sequence: AtomicU64,
+    /// Capabilities granted to each SIP that weren't revoked yet
+    granted: Lazy<Mutex<BTreeMap<SipId, Vec<String>>>>,
+}
+
+impl AuditLog {
+    pub const fn new() -> AuditLog {
+        AuditLog {
+            entries: Lazy::new(|| Mutex::new(VecDeque::with_capacity(AUDIT_LOG_SIZE))),
+            next_sequence: AtomicU64::new(0),
+            granted: Lazy::new(|| Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn record(&self, sip_id: SipId, event: AuditEvent, object: &str) {
+        if event == AuditEvent::CapabilityGranted {
+            self.granted
+                .lock()
+                .entry(sip_id)
+                .or_insert_with(Vec::new)
+                .push(object.to_string());
+        }
+
+        let mut entries = self.entries.lock();
+
+        // Discard oldest entry if log is full
+        if entries.len() >= AUDIT_LOG_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            // Taken with the entries locked, so they stay in order
+            sequence: self.next_sequence.fetch_add(1, Ordering::Relaxed),
+            timestamp: timestamp(),
+            sip_id,
+            event,
+            object: object.to_string(),
+        });
+    }
+
+    /// Record the revocation of every capability granted to `sip_id`. Called
+    /// once the SIP exited, as its capabilities die with its instance.
+    pub fn revoke_all(&self, sip_id: SipId) {
+        let granted = self.granted.lock().remove(&sip_id).unwrap_or_default();
+        for object in granted {
+            self.record(sip_id, AuditEvent::CapabilityRevoked, &object);
+        }
+    }
+
+    /// Entries matching `filter`, oldest first
+    pub fn query<F>(&self, filter: F) -> Vec<AuditEntry>
+    where
+        F: Fn(&AuditEntry) -> bool, {
+        self.entries
+            .lock()
+            .iter()
+            .filter(|entry| filter(entry))
+            .cloned()
+            .collect()
+    }
+
+    /// Entries involving `sip_id`, oldest first
+    pub fn query_sip(&self, sip_id: SipId) -> Vec<AuditEntry> {
+        self.query(|entry| entry.sip_id == sip_id)
+    }
+
+    /// Entries recorded after the entry numbered `sequence`
+    pub fn query_since(&self, sequence: u64) -> Vec<AuditEntry> {
+        self.query(|entry| entry.sequence > sequence)
+    }
+}
+
+/// Record a security relevant event in the global audit log
+pub fn record(sip_id: SipId, event: AuditEvent, object: &str) {
+    // Entries outlive the SIP they are about
+    accounting::with_tag(Tag::Kernel, || AUDIT_LOG.record(sip_id, event, object));
+}
+
+/// Record the revocation of the capabilities held by an exited SIP
+pub fn revoke_all(sip_id: SipId) {
+    accounting::with_tag(Tag::Kernel, || AUDIT_LOG.revoke_all(sip_id));
+}
+
+/// FNV-1a hash of a module, enough to tell modules apart in the log but not
+/// collision resistant
+pub fn module_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[test_case]
+fn test_audit_log_is_bounded() {
+    let log = AuditLog::new();
+    let sip_id = SipId::new();
+
+    for _ in 0..AUDIT_LOG_SIZE + 1 {
+        log.record(sip_id, AuditEvent::ModuleLoaded, "test");
+    }
+
+    let entries = log.query_sip(sip_id);
+    assert_eq!(entries.len(), AUDIT_LOG_SIZE);
+    assert_eq!(entries[0].sequence, 1);
+    assert_eq!(log.query_since(AUDIT_LOG_SIZE as u64 - 1).len(), 1);
+}
+
+#[test_case]
+fn test_revoke_all() {
+    let log = AuditLog::new();
+    let sip_id = SipId::new();
+
+    log.record(sip_id, AuditEvent::CapabilityGranted, "etheryal.wait");
+    log.record(sip_id, AuditEvent::CapabilityDenied, "etheryal.exec");
+    log.record(SipId::new(), AuditEvent::CapabilityGranted, "etheryal.notify");
+    log.revoke_all(sip_id);
+    log.revoke_all(sip_id);
+
+    let revoked = log.query(|entry| entry.event == AuditEvent::CapabilityRevoked);
+    assert_eq!(revoked.len(), 1);
+    assert_eq!(revoked[0].sip_id, sip_id);
+    assert_eq!(revoked[0].object, "etheryal.wait");
+}
+
+#[test_case]
+fn test_module_hash() {
+    assert_eq!(module_hash(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(module_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+}
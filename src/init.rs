@@ -54,21 +54,47 @@ pub fn main(boot_info: &'static mut BootInfo) -> ! {
     info!("etheryal kernel v{}", build_info::PKG_VERSION);
     info!("build with {}", build_info::RUSTC_VERSION);
 
-    unsafe {
+    let acpi_tables = unsafe {
         platform::init();
-        platform::power::create_acpi_tables(memory_offset, rsdp_address);
-    }
+        platform::power::create_acpi_tables(memory_offset, rsdp_address)
+    };
+
+    // Every core runs its own executor, sharing tasks through `tasks::smp`
+    let cpus = unsafe { platform::smp::start_cpus(&acpi_tables, init_application_processor) };
+    info!("started {} application processors", cpus);
 
-    init_scheduler();
+    // The bootloader stack has no guard page, an overflow would silently
+    // corrupt whatever lies below it
+    let stack = memory::stacks::allocate("kernel stack", KERNEL_STACK_SIZE);
+    unsafe { platform::switch_stack(stack.top(), init_scheduler) }
 }
 
-fn init_scheduler() -> ! {
+const KERNEL_STACK_SIZE: usize = 1024 * 1024;
+
+extern "C" fn init_scheduler() -> ! {
     use tasks::executor::TaskExecutor;
 
+    memory::protection::verify();
+
     let mut task_executor = TaskExecutor::new();
 
+    // Logs the polls the timer interrupt caught overrunning
+    tasks::builder::Builder::new()
+        .name("watchdog")
+        .spawn_send(tasks::spawner::spawner(), tasks::watchdog::run());
+
     // Setup init tasks
     #[cfg(test)]
     tests::register_tasks(&mut task_executor);
     task_executor.run();
 }
+
+/// Entry of the application processors, on a stack of their own
+extern "C" fn init_application_processor() -> ! {
+    use tasks::executor::TaskExecutor;
+
+    unsafe {
+        platform::init_ap();
+    }
+    TaskExecutor::new().run();
+}
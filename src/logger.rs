@@ -24,6 +24,7 @@ use crossbeam_queue::ArrayQueue;
 use log::{Log, Metadata, Record};
 use spin::{Lazy, Once};
 
+use crate::memory::accounting::{self, Tag};
 use crate::prelude::*;
 
 const KERNEL_LOG_QUEUE_SIZE: usize = 512;
@@ -63,9 +64,10 @@ impl Log for KernelLogger {
         if self.logs.is_full() {
             let _ = self.logs.pop();
         }
-        self.logs
-            .push(format!("{} - {}\n", record.level(), record.args()))
-            .expect("Failed to free log queue.");
+        let log = accounting::with_tag(Tag::Logger, || {
+            format!("{} - {}\n", record.level(), record.args())
+        });
+        self.logs.push(log).expect("Failed to free log queue.");
         self.flush();
     }
 
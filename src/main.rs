@@ -24,11 +24,12 @@
 #![feature(
     abi_x86_interrupt,
     custom_test_frameworks,
-    default_alloc_error_handler,
+    alloc_error_handler,
     const_mut_refs,
     async_closure,
     alloc_prelude,
     asm,
+    global_asm,
     once_cell,
     box_syntax,
     const_fn_fn_ptr_basics
@@ -38,6 +39,7 @@
 #![no_std]
 #![no_main]
 
+mod audit;
 mod build_info;
 mod driver;
 mod init;
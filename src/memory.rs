@@ -20,24 +20,234 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use bootloader::memory_region::{MemoryRegion, MemoryRegionKind};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use bootloader::memory_region::MemoryRegion;
 use buddy_system_allocator::LockedHeap;
+use x86_64::structures::paging::PhysFrame;
+
+use self::paging::PAGE_SIZE;
+use crate::platform::interrupts::without_interrupts;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+static PHYSICAL_MEMORY_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Memory the heap starts with, the rest of physical memory is left to the
+/// frame allocator
+const INITIAL_HEAP_SIZE: usize = 16 * 1024 * 1024;
+/// Smallest amount of memory the heap grows by
+const HEAP_GROWTH: usize = 1024 * 1024;
+
+/// Kernel heap that can also be used from interrupt handlers, e.g. when they
+/// wake a task. Interrupts are disabled while the heap is locked, so a handler
+/// can't deadlock on a lock held by the code it interrupted. Every allocation
+/// is charged to the [tag](accounting::Tag) of the code making it, small ones
+/// are served from [slab caches](slab).
+pub struct KernelAllocator {
+    heap: LockedHeap,
+    /// Bytes of physical memory given to the heap
+    size: AtomicUsize,
+}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        Self {
+            heap: LockedHeap::empty(),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add physically contiguous frames of at least `size` bytes to the heap,
+    /// starting at a multiple of `align` frames
+    fn grow(&self, size: usize, align: usize) -> bool {
+        let frames = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        match frames::allocate_contiguous(frames, align) {
+            Some(frame) => {
+                self.add_frames(frame, frames);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn add_frames(&self, frame: PhysFrame, count: usize) {
+        let start = frame.start_address().as_u64() as usize + physical_memory_offset();
+        let size = count * PAGE_SIZE as usize;
+        without_interrupts(|| unsafe { self.heap.lock().add_to_heap(start, start + size) });
+        self.size.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Allocate from the slab cache of the size class of `layout`, or from the
+    /// heap if it is too large for all of them
+    unsafe fn alloc_class(&self, layout: Layout) -> *mut u8 {
+        match slab::class_of(layout) {
+            Some(class) => slab::alloc(class, || self.alloc_block(slab::SLAB_LAYOUT)),
+            None => self.alloc_block(layout),
+        }
+    }
+
+    unsafe fn dealloc_class(&self, block: *mut u8, layout: Layout) {
+        match slab::class_of(layout) {
+            Some(class) => slab::dealloc(class, block),
+            None => self.heap.dealloc(block, layout),
+        }
+    }
+
+    /// Allocate from the heap, growing it if it is exhausted
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // The buddy allocator hands out aligned power of two blocks, add
+        // memory that is exactly such a block
+        let growth = layout
+            .size()
+            .max(layout.align())
+            .next_power_of_two()
+            .max(HEAP_GROWTH);
+        if self.grow(growth, growth / PAGE_SIZE as usize) {
+            self.heap.alloc(layout)
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Bytes of physical memory given to the heap so far
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The redzones are inside the accounting header, so an underflow
+        // hits them before the header
+        #[cfg(feature = "heap-debug")]
+        let inner_layout = match debug::block_layout(layout) {
+            Some(inner_layout) => inner_layout,
+            None => return ptr::null_mut(),
+        };
+        #[cfg(not(feature = "heap-debug"))]
+        let inner_layout = layout;
+        let block_layout = match accounting::block_layout(inner_layout) {
+            Some(block_layout) => block_layout,
+            None => return ptr::null_mut(),
+        };
+
+        without_interrupts(|| {
+            let mut block = self.alloc_class(block_layout);
+            while block.is_null() && oom::reclaim(block_layout) {
+                block = self.alloc_class(block_layout);
+            }
+
+            if block.is_null() {
+                return block;
+            }
+            let ptr = accounting::charge(block, inner_layout);
+            #[cfg(feature = "heap-debug")]
+            let ptr = debug::on_alloc(ptr, layout);
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            // The redzones are checked before the header next to them is used
+            #[cfg(feature = "heap-debug")]
+            let (size, site) = (layout.size(), debug::check(ptr, layout));
+            #[cfg(feature = "heap-debug")]
+            let (ptr, layout) = debug::raw_block(ptr, layout);
+
+            let block_layout = accounting::block_layout(layout).expect("Allocated with an invalid layout.");
+            let block = accounting::credit(ptr, layout);
+
+            // The block is quarantined, and an older one freed instead
+            #[cfg(feature = "heap-debug")]
+            let (block, block_layout) = match debug::on_free(block, block_layout, size, site) {
+                Some(evicted) => evicted,
+                None => return,
+            };
+            self.dealloc_class(block, block_layout)
+        })
+    }
+}
+
+/// Virtual address where the bootloader mapped all physical memory
+pub fn physical_memory_offset() -> usize {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
 
 pub fn init(memory_regions: &mut [MemoryRegion], offset: usize) {
-    let mut allocator = ALLOCATOR.lock();
+    PHYSICAL_MEMORY_OFFSET.store(offset, Ordering::Relaxed);
+    // The bootloader maps all physical memory at `offset`
+    unsafe { paging::init(offset) };
+
+    frames::init(memory_regions, offset);
+    accounting::init();
+    if !ALLOCATOR.grow(INITIAL_HEAP_SIZE, 1) {
+        panic!("Not enough memory for the kernel heap.");
+    }
+    oom::init();
+
+    // Every boot mapping exists by now, later ones are made without execute
+    // permission
+    unsafe { protection::enforce() };
+}
 
-    memory_regions
-        .iter()
-        .filter(|r| r.kind == MemoryRegionKind::Usable)
-        .map(|region| (region.start as usize, region.end as usize))
-        .map(|(start, end)| (start + offset, end + offset))
-        .for_each(|(start, end)| unsafe { allocator.add_to_heap(start, end) });
+/// Give the memory cached in free slabs back to the heap, returning the bytes
+/// released
+pub fn shrink_caches() -> usize {
+    without_interrupts(|| unsafe { slab::shrink(|slab| ALLOCATOR.heap.dealloc(slab, slab::SLAB_LAYOUT)) })
+}
+
+/// Whether there is room for an allocation of `size` bytes, free in the heap
+/// or in frames it can grow by. Lets allocations that may fail, like a SIP
+/// growing its memory, fail gracefully instead of exhausting the heap. It
+/// doesn't allocate, so fragmentation or allocations on other cores meanwhile
+/// can still make the allocation fail.
+pub fn can_allocate(size: usize) -> bool {
+    let heap_free = without_interrupts(|| {
+        let heap = ALLOCATOR.heap.lock();
+        heap.stats_total_bytes() - heap.stats_alloc_actual()
+    });
+    let (free_frames, _) = frames::usage();
+    heap_free.saturating_add(free_frames * PAGE_SIZE as usize) >= size
+}
+
+/// Take a block of the [out of memory reserves](oom) from the heap
+fn reserve_block(layout: Layout) -> Option<usize> {
+    let block = without_interrupts(|| unsafe { ALLOCATOR.alloc_block(layout) });
+    Some(block as usize).filter(|_| !block.is_null())
+}
+
+/// Give a block taken by [`reserve_block`] back to the heap
+unsafe fn release_block(block: usize, layout: Layout) {
+    without_interrupts(|| ALLOCATOR.heap.dealloc(block as *mut u8, layout))
+}
+
+/// Bytes of physical memory the kernel heap took from the frame allocator
+pub fn heap_size() -> usize {
+    ALLOCATOR.size()
 }
 
 #[test_case]
 fn test_allocator() {
     log::info!("{:?}", box 10)
 }
+
+pub mod accounting;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod dma;
+pub mod frames;
+pub mod oom;
+pub mod paging;
+pub mod protection;
+pub mod slab;
+pub mod stacks;
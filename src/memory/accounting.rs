@@ -0,0 +1,573 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::panic::Location;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::{mem, ptr, slice};
+
+use spin::Once;
+
+use super::paging::PAGE_SIZE;
+use super::{frames, physical_memory_offset, slab};
+use crate::platform::smp::current_cpu;
+use crate::prelude::*;
+use crate::wasm::SipId;
+
+/// Subsystem an allocation is charged to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    /// Anything not running under a more specific tag
+    Kernel,
+    Wasm,
+    Logger,
+    Tasks,
+    Sip(SipId),
+}
+
+const KERNEL_SLOT: usize = 0;
+const WASM_SLOT: usize = 1;
+const LOGGER_SLOT: usize = 2;
+const TASKS_SLOT: usize = 3;
+const FIRST_SIP_SLOT: usize = 4;
+const SLOT_COUNT: usize = 64;
+
+/// Owner of an unused SIP slot
+const FREE: u64 = 0;
+/// Set in the owner of a slot whose SIP was released while it still had
+/// allocations, the slot is freed along with the last of them
+const RETIRED: u64 = 1 << 63;
+
+/// Cores tags are tracked for, indexed by APIC ID
+const MAX_CPUS: usize = 256;
+
+/// Usage counters of a tag. The allocator can't allocate to track its own
+/// allocations, so there is a fixed number of them.
+struct Slot {
+    /// Encoded SIP ID of a SIP slot
+    owner: AtomicU64,
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    total_allocations: AtomicU64,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            owner: AtomicU64::new(FREE),
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            total_allocations: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.peak
+            .store(self.live.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.total_allocations.store(0, Ordering::Relaxed);
+    }
+
+    fn charge(&self, size: usize) {
+        let live = self.live.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.total_allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn credit(&self, size: usize) {
+        let live = self.live.fetch_sub(size, Ordering::Relaxed) - size;
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+        if live == 0 {
+            self.free_if_retired();
+        }
+    }
+
+    fn free_if_retired(&self) {
+        let owner = self.owner.load(Ordering::Relaxed);
+        if owner & RETIRED != 0 {
+            let _ = self
+                .owner
+                .compare_exchange(owner, FREE, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+const EMPTY_SLOT: Slot = Slot::new();
+static SLOTS: [Slot; SLOT_COUNT] = [EMPTY_SLOT; SLOT_COUNT];
+
+/// Tag of the code running on a core
+struct Scope {
+    slot: AtomicUsize,
+    #[cfg(feature = "alloc-tracking")]
+    site: tracking::Site,
+}
+
+impl Scope {
+    const fn new() -> Self {
+        Self {
+            slot: AtomicUsize::new(KERNEL_SLOT),
+            #[cfg(feature = "alloc-tracking")]
+            site: tracking::Site::new(),
+        }
+    }
+}
+
+const EMPTY_SCOPE: Scope = Scope::new();
+static SCOPES: [Scope; MAX_CPUS] = [EMPTY_SCOPE; MAX_CPUS];
+
+fn scope() -> &'static Scope {
+    &SCOPES[current_cpu() as usize % MAX_CPUS]
+}
+
+fn sip_owner(sip: SipId) -> u64 {
+    sip.as_u64() + 1
+}
+
+/// Slot of `tag`, claiming one for a new SIP. SIPs share the slot of
+/// [`Tag::Wasm`] once all slots are taken.
+fn slot_of(tag: Tag) -> usize {
+    let sip = match tag {
+        Tag::Kernel => return KERNEL_SLOT,
+        Tag::Wasm => return WASM_SLOT,
+        Tag::Logger => return LOGGER_SLOT,
+        Tag::Tasks => return TASKS_SLOT,
+        Tag::Sip(sip) => sip,
+    };
+
+    let owner = sip_owner(sip);
+    let sip_slots = FIRST_SIP_SLOT..SLOT_COUNT;
+    if let Some(index) = sip_slots
+        .clone()
+        .find(|&index| SLOTS[index].owner.load(Ordering::Relaxed) == owner)
+    {
+        return index;
+    }
+
+    for index in sip_slots {
+        let claimed = SLOTS[index]
+            .owner
+            .compare_exchange(FREE, owner, Ordering::Relaxed, Ordering::Relaxed);
+        if claimed.is_ok() {
+            SLOTS[index].reset();
+            return index;
+        }
+    }
+    WASM_SLOT
+}
+
+fn tag_of(index: usize) -> Option<Tag> {
+    match index {
+        KERNEL_SLOT => Some(Tag::Kernel),
+        WASM_SLOT => Some(Tag::Wasm),
+        LOGGER_SLOT => Some(Tag::Logger),
+        TASKS_SLOT => Some(Tag::Tasks),
+        _ => match SLOTS[index].owner.load(Ordering::Relaxed) & !RETIRED {
+            FREE => None,
+            owner => Some(Tag::Sip(SipId::from_u64(owner - 1))),
+        },
+    }
+}
+
+/// Charges allocations on this core to a tag until it is dropped. Held across
+/// an `.await`, it would charge whatever else the core runs meanwhile, use
+/// [`tagged`] for futures.
+#[must_use]
+pub struct TagGuard {
+    previous: usize,
+    #[cfg(feature = "alloc-tracking")]
+    previous_site: Option<&'static Location<'static>>,
+    // Restored on the core it was entered on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let scope = scope();
+        scope.slot.store(self.previous, Ordering::Relaxed);
+        #[cfg(feature = "alloc-tracking")]
+        scope.site.set(self.previous_site);
+    }
+}
+
+/// Charge allocations on this core to `tag` until the guard is dropped
+#[track_caller]
+pub fn enter(tag: Tag) -> TagGuard {
+    enter_at(tag, Location::caller())
+}
+
+/// `location` is recorded as the call site of allocations with tracking on
+#[cfg_attr(not(feature = "alloc-tracking"), allow(unused_variables))]
+fn enter_at(tag: Tag, location: &'static Location<'static>) -> TagGuard {
+    let scope = scope();
+    let previous = scope.slot.swap(slot_of(tag), Ordering::Relaxed);
+
+    TagGuard {
+        previous,
+        #[cfg(feature = "alloc-tracking")]
+        previous_site: scope.site.replace(Some(location)),
+        _not_send: PhantomData,
+    }
+}
+
+/// Run `f` with its allocations charged to `tag`
+#[track_caller]
+pub fn with_tag<R>(tag: Tag, f: impl FnOnce() -> R) -> R {
+    let _guard = enter(tag);
+    f()
+}
+
+/// Future charging the allocations of every poll of `future` to a tag
+pub struct Tagged<F> {
+    tag: Tag,
+    location: &'static Location<'static>,
+    future: F,
+}
+
+impl<F> Future for Tagged<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = enter_at(self.tag, self.location);
+        // The future is pinned along with `Tagged`, which never moves it out
+        let future = unsafe { self.map_unchecked_mut(|tagged| &mut tagged.future) };
+        future.poll(cx)
+    }
+}
+
+/// Charge the allocations of `future` to `tag`, whenever it is polled
+#[track_caller]
+pub fn tagged<F>(tag: Tag, future: F) -> Tagged<F>
+where
+    F: Future, {
+    Tagged {
+        tag,
+        location: Location::caller(),
+        future,
+    }
+}
+
+/// Stop accounting for an exited SIP. Its slot is reused once its remaining
+/// allocations are freed, the leaked bytes are returned.
+pub fn release(sip: SipId) -> usize {
+    let owner = sip_owner(sip);
+    let slot = match SLOTS[FIRST_SIP_SLOT..]
+        .iter()
+        .find(|slot| slot.owner.load(Ordering::Relaxed) == owner)
+    {
+        Some(slot) => slot,
+        None => return 0,
+    };
+
+    slot.owner.fetch_or(RETIRED, Ordering::Relaxed);
+    let live = slot.live.load(Ordering::Relaxed);
+    if live == 0 {
+        slot.free_if_retired();
+    }
+    live
+}
+
+/// Prepended to allocations served by the slab caches, so they are credited to
+/// the tag they were charged to wherever they are freed. Larger allocations
+/// keep their slot in [`LARGE_SLOTS`] instead: the heap rounds blocks up to a
+/// power of two, so a header would double the size of every page buffer.
+///
+/// With `alloc-tracking`, every allocation has a header, as it links the list
+/// of live allocations.
+#[repr(C)]
+struct Header {
+    slot: usize,
+    #[cfg(feature = "alloc-tracking")]
+    allocation: tracking::Allocation,
+}
+
+/// Allocations without a header are served by the heap in blocks at least
+/// this large and aligned, so no two of them start in the same granule
+const GRANULE: usize = slab::MAX_OBJECT_SIZE;
+
+/// Slot of every allocation without a header, by the granule of physical
+/// memory it starts in
+static LARGE_SLOTS: Once<&'static [AtomicU8]> = Once::new();
+
+/// Take the memory for the slots of allocations without a header from the
+/// frame allocator. Must be called before the heap is used.
+pub(super) fn init() {
+    let granules = frames::memory_end().as_u64() as usize / GRANULE;
+    let pages = (granules + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+    let frame =
+        frames::allocate_contiguous(pages.max(1), 1).expect("Not enough memory for the accounting slots.");
+
+    let start = (frame.start_address().as_u64() as usize + physical_memory_offset()) as *mut AtomicU8;
+    unsafe {
+        ptr::write_bytes(start, 0, granules);
+        LARGE_SLOTS.call_once(|| slice::from_raw_parts(start, granules));
+    }
+}
+
+fn large_slot(ptr: *mut u8) -> &'static AtomicU8 {
+    let slots = LARGE_SLOTS.get().expect("Accounting is not initialized.");
+    &slots[(ptr as usize - physical_memory_offset()) / GRANULE]
+}
+
+/// Offset of an allocation from the start of its block, which holds its
+/// header
+fn header_offset(layout: Layout) -> usize {
+    let size = mem::size_of::<Header>();
+    (size + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// Whether an allocation of `layout` is prepended a header, only those served
+/// by a slab cache are
+fn has_header(layout: Layout) -> bool {
+    if cfg!(feature = "alloc-tracking") {
+        return true;
+    }
+    layout
+        .size()
+        .checked_add(header_offset(layout))
+        .and_then(|size| Layout::from_size_align(size, layout.align()).ok())
+        .and_then(slab::class_of)
+        .is_some()
+}
+
+/// Offset of an allocation from the start of its block
+fn offset(layout: Layout) -> usize {
+    if has_header(layout) {
+        header_offset(layout)
+    } else {
+        0
+    }
+}
+
+/// Layout of the block holding an allocation of `layout` and its header, if
+/// it has one
+pub(super) fn block_layout(layout: Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(offset(layout))?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Charge an allocation of `layout` to the current tag, returning its address
+/// in `block`
+///
+/// # Safety
+/// `block` must be a block of [`block_layout`] of `layout`
+pub(super) unsafe fn charge(block: *mut u8, layout: Layout) -> *mut u8 {
+    let scope = scope();
+    let slot = scope.slot.load(Ordering::Relaxed);
+    SLOTS[slot].charge(layout.size());
+
+    if !has_header(layout) {
+        large_slot(block).store(slot as u8, Ordering::Relaxed);
+        return block;
+    }
+
+    let ptr = block.add(header_offset(layout));
+    let header = ptr.cast::<Header>().sub(1);
+    header.write(Header {
+        slot,
+        #[cfg(feature = "alloc-tracking")]
+        allocation: tracking::Allocation::new(scope.site.get(), layout.size()),
+    });
+    #[cfg(feature = "alloc-tracking")]
+    tracking::insert(&mut (*header).allocation);
+    ptr
+}
+
+/// Call site the allocation at `ptr` was made from
+///
+/// # Safety
+/// `ptr` must be returned by [`charge`] and not freed yet
+#[cfg(feature = "alloc-tracking")]
+pub(super) unsafe fn site(ptr: *mut u8) -> Option<&'static Location<'static>> {
+    (*ptr.cast::<Header>().sub(1)).allocation.site()
+}
+
+/// Credit the allocation at `ptr` to the tag it was charged to, returning its
+/// block
+///
+/// # Safety
+/// `ptr` must be returned by [`charge`] for `layout`
+pub(super) unsafe fn credit(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    if !has_header(layout) {
+        let slot = large_slot(ptr).load(Ordering::Relaxed);
+        SLOTS[usize::from(slot)].credit(layout.size());
+        return ptr;
+    }
+
+    let header = ptr.cast::<Header>().sub(1);
+    #[cfg(feature = "alloc-tracking")]
+    tracking::remove(&mut (*header).allocation);
+
+    SLOTS[(*header).slot].credit(layout.size());
+    ptr.sub(header_offset(layout))
+}
+
+/// Heap usage of a tag
+#[derive(Debug, Clone, Copy)]
+pub struct TagUsage {
+    pub tag: Tag,
+    /// Bytes currently allocated
+    pub live: usize,
+    /// Most bytes allocated at once
+    pub peak: usize,
+    /// Allocations not freed yet
+    pub allocations: usize,
+    pub total_allocations: u64,
+}
+
+/// Heap usage of every tag with memory allocated
+pub fn usage() -> Vec<TagUsage> {
+    let mut usage = Vec::new();
+    for (index, slot) in SLOTS.iter().enumerate() {
+        let tag = match tag_of(index) {
+            Some(tag) => tag,
+            None => continue,
+        };
+        usage.push(TagUsage {
+            tag,
+            live: slot.live.load(Ordering::Relaxed),
+            peak: slot.peak.load(Ordering::Relaxed),
+            allocations: slot.allocations.load(Ordering::Relaxed),
+            total_allocations: slot.total_allocations.load(Ordering::Relaxed),
+        });
+    }
+    usage
+}
+
+/// SIP the code running on this core allocates for, if any
+pub(super) fn current_sip() -> Option<SipId> {
+    match tag_of(scope().slot.load(Ordering::Relaxed)) {
+        Some(Tag::Sip(sip)) => Some(sip),
+        _ => None,
+    }
+}
+
+/// Running SIP with the most bytes allocated. It doesn't allocate, so it can
+/// be used when the heap is exhausted.
+pub(super) fn largest_sip() -> Option<SipId> {
+    let mut largest: Option<(SipId, usize)> = None;
+
+    for slot in &SLOTS[FIRST_SIP_SLOT..] {
+        let owner = slot.owner.load(Ordering::Relaxed);
+        if owner == FREE || owner & RETIRED != 0 {
+            continue;
+        }
+        let live = slot.live.load(Ordering::Relaxed);
+        if largest.map_or(true, |(_, bytes)| live > bytes) {
+            largest = Some((SipId::from_u64(owner - 1), live));
+        }
+    }
+    largest.map(|(sip, _)| sip)
+}
+
+/// Heap usage of `tag`
+pub fn usage_of(tag: Tag) -> Option<TagUsage> {
+    usage().into_iter().find(|usage| usage.tag == tag)
+}
+
+/// Log the heap usage of every tag
+pub fn dump() {
+    info!(
+        "{:<12} {:>12} {:>12} {:>10} {:>12}",
+        "tag", "live", "peak", "allocs", "total allocs"
+    );
+    for usage in usage() {
+        info!(
+            "{:<12} {:>12} {:>12} {:>10} {:>12}",
+            format!("{:?}", usage.tag),
+            usage.live,
+            usage.peak,
+            usage.allocations,
+            usage.total_allocations
+        );
+    }
+}
+
+#[test_case]
+fn test_tag_accounting() {
+    use alloc::boxed::Box;
+
+    let sip = SipId::new();
+    let allocation = with_tag(Tag::Sip(sip), || Box::new([0u8; 1000]));
+    let usage = usage_of(Tag::Sip(sip)).expect("SIP has no accounting slot");
+    assert_eq!((usage.live, usage.allocations), (1000, 1));
+
+    drop(allocation);
+    assert_eq!(release(sip), 0);
+    assert!(usage_of(Tag::Sip(sip)).is_none());
+}
+
+#[test_case]
+fn test_large_allocation_not_padded() {
+    use alloc::vec::Vec;
+
+    let page = Layout::from_size_align(4096, 4096).unwrap();
+    if !cfg!(feature = "alloc-tracking") {
+        assert_eq!(block_layout(page), Some(page));
+    }
+
+    let sip = SipId::new();
+    let allocation = with_tag(Tag::Sip(sip), || Vec::<u8>::with_capacity(64 * 1024));
+    let usage = usage_of(Tag::Sip(sip)).expect("SIP has no accounting slot");
+    assert_eq!((usage.live, usage.allocations), (64 * 1024, 1));
+
+    drop(allocation);
+    assert_eq!(release(sip), 0);
+}
+
+#[test_case]
+fn test_tagged_future() {
+    use alloc::boxed::Box;
+
+    use futures::future::poll_fn;
+    use futures::pin_mut;
+    use futures::task::noop_waker_ref;
+
+    let sip = SipId::new();
+    // Async blocks are not `Unpin`
+    let future = tagged(Tag::Sip(sip), async {
+        let value = Box::new(7u64);
+        poll_fn(|_| Poll::Ready(())).await;
+        *value
+    });
+    pin_mut!(future);
+
+    let mut context = Context::from_waker(noop_waker_ref());
+    assert_eq!(future.poll(&mut context), Poll::Ready(7));
+    assert_eq!(
+        usage_of(Tag::Sip(sip)).map(|usage| usage.total_allocations),
+        Some(1)
+    );
+    assert_eq!(release(sip), 0);
+}
+
+#[cfg(feature = "alloc-tracking")]
+pub mod tracking;
@@ -0,0 +1,214 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use spin::Mutex;
+
+use super::scope;
+use crate::platform::interrupts::without_interrupts;
+use crate::prelude::*;
+
+/// Most call sites reported at once, the list of live allocations can't be
+/// grouped into an allocated map while it is locked
+const MAX_SITES: usize = 64;
+
+/// Call site allocations on a core are recorded with, the innermost tag scope
+/// or the task being polled
+pub(super) struct Site(AtomicPtr<Location<'static>>);
+
+impl Site {
+    pub(super) const fn new() -> Self {
+        Site(AtomicPtr::new(ptr::null_mut()))
+    }
+
+    pub(super) fn get(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.0.load(Ordering::Relaxed).as_ref() }
+    }
+
+    pub(super) fn set(&self, site: Option<&'static Location<'static>>) {
+        self.0.store(as_ptr(site), Ordering::Relaxed);
+    }
+
+    pub(super) fn replace(
+        &self, site: Option<&'static Location<'static>>,
+    ) -> Option<&'static Location<'static>> {
+        unsafe { self.0.swap(as_ptr(site), Ordering::Relaxed).as_ref() }
+    }
+}
+
+fn as_ptr(site: Option<&'static Location<'static>>) -> *mut Location<'static> {
+    site.map_or(ptr::null_mut(), |site| site as *const _ as *mut _)
+}
+
+/// Entry of the list of live allocations, kept in their headers
+#[repr(C)]
+pub(super) struct Allocation {
+    site: Option<&'static Location<'static>>,
+    size: usize,
+    prev: *mut Allocation,
+    next: *mut Allocation,
+}
+
+impl Allocation {
+    pub(super) fn new(site: Option<&'static Location<'static>>, size: usize) -> Self {
+        Self {
+            site,
+            size,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        }
+    }
+
+    pub(super) fn site(&self) -> Option<&'static Location<'static>> {
+        self.site
+    }
+}
+
+struct LiveList {
+    head: *mut Allocation,
+}
+
+// Only accessed with the lock held
+unsafe impl Send for LiveList {}
+
+static LIVE: Mutex<LiveList> = Mutex::new(LiveList {
+    head: ptr::null_mut(),
+});
+
+/// Add an allocation to the live list
+///
+/// # Safety
+/// `allocation` must stay valid until it is removed
+pub(super) unsafe fn insert(allocation: *mut Allocation) {
+    without_interrupts(|| {
+        let mut live = LIVE.lock();
+        (*allocation).next = live.head;
+        if let Some(head) = live.head.as_mut() {
+            head.prev = allocation;
+        }
+        live.head = allocation;
+    })
+}
+
+/// Remove an allocation from the live list
+///
+/// # Safety
+/// `allocation` must have been inserted
+pub(super) unsafe fn remove(allocation: *mut Allocation) {
+    without_interrupts(|| {
+        let mut live = LIVE.lock();
+        let Allocation { prev, next, .. } = *allocation;
+        match prev.as_mut() {
+            Some(prev) => prev.next = next,
+            None => live.head = next,
+        }
+        if let Some(next) = next.as_mut() {
+            next.prev = prev;
+        }
+    })
+}
+
+/// Records the spawn location of a task as the call site of its allocations
+/// until it is dropped
+#[must_use]
+pub struct SiteGuard {
+    previous: Option<&'static Location<'static>>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for SiteGuard {
+    fn drop(&mut self) {
+        scope().site.set(self.previous);
+    }
+}
+
+/// Record `site` as the call site of allocations on this core, until a tag
+/// scope records a more specific one
+pub fn enter_site(site: &'static Location<'static>) -> SiteGuard {
+    SiteGuard {
+        previous: scope().site.replace(Some(site)),
+        _not_send: PhantomData,
+    }
+}
+
+/// Live allocations of a call site
+#[derive(Debug, Clone, Copy)]
+pub struct SiteUsage {
+    /// `None` for allocations outside of any tag scope or task
+    pub site: Option<&'static Location<'static>>,
+    pub bytes: usize,
+    pub allocations: usize,
+}
+
+/// Live allocations grouped by call site, most bytes first. Allocations of
+/// sites beyond the first [`MAX_SITES`] found are left out.
+pub fn sites() -> Vec<SiteUsage> {
+    let empty = SiteUsage {
+        site: None,
+        bytes: 0,
+        allocations: 0,
+    };
+    let mut sites = [empty; MAX_SITES];
+    let mut count = 0;
+
+    without_interrupts(|| {
+        let live = LIVE.lock();
+        let mut next = live.head;
+
+        while let Some(allocation) = unsafe { next.as_ref() } {
+            next = allocation.next;
+
+            let same_site = |usage: &SiteUsage| as_ptr(usage.site) == as_ptr(allocation.site);
+            let usage = match sites[..count].iter().position(same_site) {
+                Some(index) => &mut sites[index],
+                None if count < MAX_SITES => {
+                    sites[count].site = allocation.site;
+                    count += 1;
+                    &mut sites[count - 1]
+                },
+                None => continue,
+            };
+            usage.bytes += allocation.size;
+            usage.allocations += 1;
+        }
+    });
+
+    let mut sites = sites[..count].to_vec();
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+    sites
+}
+
+/// Log the call sites holding the most memory, to find leaks
+pub fn dump(limit: usize) {
+    info!("{:>12} {:>10}  site", "bytes", "allocs");
+    for usage in sites().into_iter().take(limit) {
+        match usage.site {
+            Some(site) => info!("{:>12} {:>10}  {}", usage.bytes, usage.allocations, site),
+            None => info!("{:>12} {:>10}  -", usage.bytes, usage.allocations),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::alloc::Layout;
+use core::panic::Location;
+use core::{fmt, ptr, slice};
+
+use spin::Mutex;
+
+/// Bytes checked after every block, and at least before it
+const REDZONE: usize = 16;
+const REDZONE_POISON: u8 = 0xFD;
+/// Fill of fresh allocations, so reads of uninitialized memory stand out
+const ALLOC_POISON: u8 = 0xA5;
+/// Fill of freed blocks, checked when they leave the quarantine
+const FREE_POISON: u8 = 0xDD;
+
+/// Freed blocks held back from reuse, so writes through dangling pointers are
+/// noticed
+const QUARANTINE_SIZE: usize = 1024;
+/// Larger blocks are checked but not quarantined, they would hold too much
+/// memory
+const MAX_QUARANTINED: usize = 64 * 1024;
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine::new());
+
+/// Freed heap block, with the accounting header the allocation had
+#[derive(Clone, Copy)]
+struct Quarantined {
+    block: *mut u8,
+    block_layout: Layout,
+    size: usize,
+    site: Option<&'static Location<'static>>,
+}
+
+impl Quarantined {
+    /// Whether the block still holds only the poison it was filled with
+    unsafe fn is_intact(&self) -> bool {
+        is_filled(self.block, self.block_layout.size(), FREE_POISON)
+    }
+}
+
+/// Ring of the most recently freed blocks
+struct Quarantine {
+    blocks: [Option<Quarantined>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+// Only accessed with the lock held
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    const fn new() -> Self {
+        const EMPTY: Option<Quarantined> = None;
+        Self {
+            blocks: [EMPTY; QUARANTINE_SIZE],
+            next: 0,
+        }
+    }
+
+    /// Add a block, returning the oldest one once the quarantine is full
+    fn push(&mut self, block: Quarantined) -> Option<Quarantined> {
+        let evicted = self.blocks[self.next].replace(block);
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        evicted
+    }
+}
+
+/// Where a corrupted block was allocated, for the panic message
+struct Site(Option<&'static Location<'static>>);
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(location) => write!(f, "allocated at {}", location),
+            None => write!(f, "allocated at an unknown site"),
+        }
+    }
+}
+
+/// Redzone before a block, keeping it aligned
+fn front(layout: Layout) -> usize {
+    REDZONE.max(layout.align())
+}
+
+/// Layout of a block of `layout` with its redzones
+pub(super) fn block_layout(layout: Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(front(layout) + REDZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+unsafe fn is_filled(start: *const u8, len: usize, value: u8) -> bool {
+    slice::from_raw_parts(start, len)
+        .iter()
+        .all(|&byte| byte == value)
+}
+
+/// Poison a fresh block of `layout` and its redzones, returning the block.
+/// The redzones are within the accounting header, directly around the block.
+///
+/// # Safety
+/// `raw` must be allocated for [`block_layout`] of `layout`
+pub(super) unsafe fn on_alloc(raw: *mut u8, layout: Layout) -> *mut u8 {
+    let front = front(layout);
+    ptr::write_bytes(raw, REDZONE_POISON, front);
+    ptr::write_bytes(raw.add(front), ALLOC_POISON, layout.size());
+    ptr::write_bytes(raw.add(front + layout.size()), REDZONE_POISON, REDZONE);
+    raw.add(front)
+}
+
+/// Start and layout of the redzoned block of `block`, as passed to
+/// [`on_alloc`]
+pub(super) unsafe fn raw_block(block: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let raw_layout = block_layout(layout).expect("Allocated with an invalid layout.");
+    (block.sub(front(layout)), raw_layout)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Corruption {
+    /// Written before the block
+    Underflow,
+    /// Written after the block
+    Overflow,
+}
+
+unsafe fn corruption(block: *mut u8, layout: Layout) -> Option<Corruption> {
+    let front = front(layout);
+    if !is_filled(block.sub(front), front, REDZONE_POISON) {
+        Some(Corruption::Underflow)
+    } else if !is_filled(block.add(layout.size()), REDZONE, REDZONE_POISON) {
+        Some(Corruption::Overflow)
+    } else {
+        None
+    }
+}
+
+/// Check the redzones of a block being freed, before its accounting header is
+/// used. Returns the allocation site from the header.
+///
+/// Panics naming the allocation site if a redzone was overwritten. The site is
+/// unknown if an underflow reached past the redzone into the header.
+///
+/// # Safety
+/// `block` must be returned by [`on_alloc`] for `layout`, inside a block
+/// charged by [`accounting::charge`](super::accounting::charge)
+pub(super) unsafe fn check(block: *mut u8, layout: Layout) -> Option<&'static Location<'static>> {
+    let (raw, _) = raw_block(block, layout);
+    let corruption = match corruption(block, layout) {
+        Some(corruption) => corruption,
+        None => return super::accounting::site(raw),
+    };
+
+    let site = if *raw == REDZONE_POISON {
+        super::accounting::site(raw)
+    } else {
+        None
+    };
+    let written = match corruption {
+        Corruption::Underflow => "before",
+        Corruption::Overflow => "after",
+    };
+    panic!(
+        "heap corruption: written {} a block of {} bytes {}",
+        written,
+        layout.size(),
+        Site(site)
+    );
+}
+
+/// Poison a freed heap block and quarantine it. `size` and `site` describe the
+/// allocation in it. Returns the block and layout to free for real, the oldest
+/// one in quarantine.
+///
+/// Panics naming the allocation site if that block was written to after it was
+/// freed.
+///
+/// # Safety
+/// `block` must be a heap block of `block_layout` that is no longer used
+pub(super) unsafe fn on_free(
+    block: *mut u8, block_layout: Layout, size: usize, site: Option<&'static Location<'static>>,
+) -> Option<(*mut u8, Layout)> {
+    ptr::write_bytes(block, FREE_POISON, block_layout.size());
+
+    if size > MAX_QUARANTINED {
+        return Some((block, block_layout));
+    }
+    let quarantined = Quarantined {
+        block,
+        block_layout,
+        size,
+        site,
+    };
+    // Checked after unlocking, panicking logs and so frees memory
+    let evicted = QUARANTINE.lock().push(quarantined)?;
+
+    if !evicted.is_intact() {
+        panic!(
+            "use after free: a block of {} bytes {} was written to after it was freed",
+            evicted.size,
+            Site(evicted.site)
+        );
+    }
+    Some((evicted.block, evicted.block_layout))
+}
+
+#[test_case]
+fn test_fresh_allocation_poisoned() {
+    use alloc::alloc::{alloc, dealloc};
+
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!(is_filled(ptr, layout.size(), ALLOC_POISON));
+        dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn test_redzone_corruption() {
+    use alloc::vec;
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let raw_layout = block_layout(layout).unwrap();
+    let mut buffer = vec![0u64; raw_layout.size() / 8 + 1];
+    unsafe {
+        let block = on_alloc(buffer.as_mut_ptr().cast(), layout);
+        assert_eq!(corruption(block, layout), None);
+
+        *block.add(layout.size()) = 0;
+        assert_eq!(corruption(block, layout), Some(Corruption::Overflow));
+        *block.add(layout.size()) = REDZONE_POISON;
+        *block.sub(1) = 0;
+        assert_eq!(corruption(block, layout), Some(Corruption::Underflow));
+    }
+}
+
+#[test_case]
+fn test_write_after_free() {
+    use alloc::vec;
+
+    let mut buffer = vec![0u8; 64];
+    let quarantined = Quarantined {
+        block: buffer.as_mut_ptr(),
+        block_layout: Layout::from_size_align(64, 1).unwrap(),
+        size: 64,
+        site: None,
+    };
+    unsafe {
+        ptr::write_bytes(quarantined.block, FREE_POISON, 64);
+        assert!(quarantined.is_intact());
+        *quarantined.block.add(40) = 1;
+        assert!(!quarantined.is_intact());
+    }
+}
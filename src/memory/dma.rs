@@ -0,0 +1,225 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::arch::x86_64::{_mm_clflush, _mm_mfence};
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::paging::{self, PAGE_SIZE};
+use super::{frames, physical_memory_offset};
+
+/// Largest buffer handed out, bigger ones are unlikely to find contiguous
+/// frames and should be split into scatter-gather lists
+pub const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+/// Cache lines are flushed in steps of this size, the smallest line size of
+/// x86_64 processors
+const CACHE_LINE_SIZE: usize = 64;
+
+/// Placement requirements of a device for a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constraints {
+    /// Alignment of the physical address, a power of two. Buffers are always
+    /// page aligned.
+    pub align: usize,
+    /// Power of two the buffer must not cross a multiple of, e.g. 64 KiB for
+    /// PRDs of AHCI
+    pub boundary: Option<usize>,
+    /// Map the buffer uncached, for device registers or descriptor rings of
+    /// devices that don't snoop the cache
+    pub uncached: bool,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self {
+            align: PAGE_SIZE as usize,
+            boundary: None,
+            uncached: false,
+        }
+    }
+}
+
+/// Reason a DMA buffer couldn't be allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The size is zero, or larger than [`MAX_SIZE`] or the boundary
+    InvalidSize,
+    /// An alignment or boundary that isn't a power of two
+    InvalidConstraints,
+    /// No physically contiguous range satisfies the constraints
+    OutOfMemory,
+}
+
+/// Physically contiguous buffer a device can access. It is zeroed when
+/// allocated and freed when dropped, the device must not use it anymore by
+/// then.
+pub struct DmaBuffer {
+    virt: NonNull<u8>,
+    phys: PhysAddr,
+    size: usize,
+    uncached: bool,
+}
+
+// The buffer is owned memory, like a `Box<[u8]>`
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt.as_ptr()
+    }
+
+    /// Address to program into the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Size in bytes, rounded up to whole pages
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_uncached(&self) -> bool {
+        self.uncached
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+
+    fn frames(&self) -> usize {
+        self.size / PAGE_SIZE as usize
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.uncached {
+            let virt = VirtAddr::from_ptr(self.as_ptr());
+            set_uncached(virt, self.size, false).expect("DMA buffer was unmapped.");
+        }
+        frames::deallocate_contiguous(PhysFrame::containing_address(self.phys), self.frames());
+    }
+}
+
+/// Allocate a buffer of at least `size` bytes placed as `constraints` require
+pub fn allocate(size: usize, constraints: Constraints) -> Result<DmaBuffer, DmaError> {
+    if size == 0 || size > MAX_SIZE {
+        return Err(DmaError::InvalidSize);
+    }
+    if !constraints.align.is_power_of_two() {
+        return Err(DmaError::InvalidConstraints);
+    }
+    let size = (size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize * PAGE_SIZE as usize;
+
+    // A buffer aligned to a power of two at least its size doesn't cross any
+    // larger power of two
+    let mut align = constraints.align.max(PAGE_SIZE as usize);
+    if let Some(boundary) = constraints.boundary {
+        if !boundary.is_power_of_two() {
+            return Err(DmaError::InvalidConstraints);
+        }
+        if size > boundary {
+            return Err(DmaError::InvalidSize);
+        }
+        align = align.max(size.next_power_of_two());
+    }
+
+    let frames = size / PAGE_SIZE as usize;
+    let frame =
+        frames::allocate_contiguous(frames, align / PAGE_SIZE as usize).ok_or(DmaError::OutOfMemory)?;
+    let phys = frame.start_address();
+
+    let virt = VirtAddr::new(phys.as_u64() + physical_memory_offset() as u64);
+    if constraints.uncached && set_uncached(virt, size, true).is_none() {
+        frames::deallocate_contiguous(frame, frames);
+        return Err(DmaError::OutOfMemory);
+    }
+
+    unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size) };
+    Ok(DmaBuffer {
+        virt: NonNull::new(virt.as_mut_ptr()).expect("DMA buffer mapped at null."),
+        phys,
+        size,
+        uncached: constraints.uncached,
+    })
+}
+
+/// Enable or disable caching of `size` bytes at `virt` in the physical memory
+/// mapping. A buffer has no other mapping, so its memory never has two memory
+/// types. Huge pages of the mapping are split first.
+fn set_uncached(virt: VirtAddr, size: usize, uncached: bool) -> Option<()> {
+    paging::split_huge_pages(virt, size as u64).ok()?;
+
+    let (_, flags) = paging::translate(virt)?;
+    let caching = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let flags = if uncached {
+        flags | caching
+    } else {
+        flags - caching
+    };
+    unsafe { paging::protect(virt, size as u64, flags) }.ok()?;
+
+    // Lines cached while the memory was write-back would otherwise be written
+    // back over data from the device later
+    if uncached {
+        for offset in (0..size).step_by(CACHE_LINE_SIZE) {
+            unsafe { _mm_clflush(virt.as_ptr::<u8>().add(offset)) };
+        }
+        unsafe { _mm_mfence() };
+    }
+    Some(())
+}
+
+#[test_case]
+fn test_dma_boundary() {
+    let constraints = Constraints {
+        boundary: Some(64 * 1024),
+        uncached: true,
+        ..Constraints::default()
+    };
+    let buffer = allocate(3 * PAGE_SIZE as usize, constraints).expect("no DMA buffer");
+    let start = buffer.phys_addr().as_u64();
+    let end = start + buffer.size() as u64 - 1;
+
+    assert_eq!(start / (64 * 1024), end / (64 * 1024));
+    let virt = VirtAddr::from_ptr(buffer.as_ptr());
+    let (phys, flags) = paging::translate(virt).expect("DMA buffer is not mapped");
+    assert_eq!(phys, buffer.phys_addr());
+    assert!(flags.contains(PageTableFlags::NO_CACHE));
+
+    drop(buffer);
+    let (_, flags) = paging::translate(virt).expect("physical memory mapping was removed");
+    assert!(!flags.contains(PageTableFlags::NO_CACHE));
+    assert_eq!(
+        allocate(MAX_SIZE + 1, Constraints::default()).err(),
+        Some(DmaError::InvalidSize)
+    );
+}
@@ -0,0 +1,271 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::slice;
+
+use bootloader::memory_region::{MemoryRegion, MemoryRegionKind};
+use spin::{Mutex, Once};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::paging::PAGE_SIZE;
+use crate::platform::interrupts::without_interrupts;
+
+/// Physical frames not handed to anything else. Heap growth may take frames
+/// from interrupt handlers, so interrupts are disabled while it is locked.
+static FRAMES: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+/// Tracks every physical frame below the end of the last usable region with
+/// one bit, set while the frame is in use or not usable at all
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Where the last search ended, single frames are searched from here
+    next: usize,
+    free: usize,
+    usable: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build the allocator over the usable `regions`. The bitmap itself is
+    /// stored at the start of the first usable region large enough for it.
+    ///
+    /// # Safety
+    /// Usable regions must be unused, and physical memory must be mapped at
+    /// `offset`.
+    unsafe fn new(regions: &[MemoryRegion], offset: usize) -> Self {
+        let usable = || {
+            regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+        };
+
+        let frames = usable().map(|region| region.end / PAGE_SIZE).max().unwrap_or(0) as usize;
+        let words = (frames + 63) / 64;
+        let bitmap_bytes = (words * 8) as u64;
+        let bitmap_region = usable()
+            .find(|region| region.end - align_up(region.start, PAGE_SIZE) >= bitmap_bytes)
+            .expect("No memory region can hold the frame bitmap.");
+        let bitmap_start = align_up(bitmap_region.start, PAGE_SIZE);
+
+        let bitmap = slice::from_raw_parts_mut((bitmap_start as usize + offset) as *mut u64, words);
+        bitmap.iter_mut().for_each(|word| *word = !0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            next: 0,
+            free: 0,
+            usable: 0,
+        };
+
+        // Regions may start or end in the middle of a frame, only whole frames
+        // inside them are usable
+        for region in usable() {
+            let first = align_up(region.start, PAGE_SIZE) / PAGE_SIZE;
+            let end = region.end / PAGE_SIZE;
+            for frame in first..end {
+                allocator.set_used(frame as usize, false);
+            }
+        }
+        allocator.usable = allocator.free;
+
+        let bitmap_first = (bitmap_start / PAGE_SIZE) as usize;
+        let bitmap_frames = ((bitmap_bytes + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.set_used(frame, true);
+        }
+        allocator
+    }
+
+    fn frames(&self) -> usize {
+        self.bitmap.len() * 64
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if self.is_used(frame) == used {
+            return;
+        }
+
+        let word = &mut self.bitmap[frame / 64];
+        if used {
+            *word |= 1 << (frame % 64);
+            self.free -= 1;
+        } else {
+            *word &= !(1 << (frame % 64));
+            self.free += 1;
+        }
+    }
+
+    /// First run of `count` free frames in `from..to` starting at a multiple
+    /// of `align` frames
+    fn search(&self, from: usize, to: usize, count: usize, align: usize) -> Option<usize> {
+        let mut start = align_up(from as u64, align as u64) as usize;
+
+        while start + count <= to {
+            // Skip over fully used words when looking for a single frame
+            if count == 1 && start % 64 == 0 && self.bitmap[start / 64] == !0 {
+                start += 64;
+                continue;
+            }
+
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                Some(used) => start = align_up(used as u64 + 1, align as u64) as usize,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(1, 1)
+    }
+
+    /// Allocate `count` physically contiguous frames, the first one aligned
+    /// to `align` frames, which must be a power of two
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "frame alignment must be a power of two");
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let frames = self.frames();
+        let start = self
+            .search(self.next, frames, count, align)
+            .or_else(|| self.search(0, frames, count, align))?;
+
+        for frame in start..start + count {
+            self.set_used(frame, true);
+        }
+        self.next = start + count;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            start as u64 * PAGE_SIZE,
+        )))
+    }
+
+    /// Allocate a frame below `limit`, e.g. for code that runs before paging
+    /// is enabled. Frame zero is never handed out.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / PAGE_SIZE) as usize).min(self.frames());
+        let frame = self.search(1, end, 1, 1)?;
+        self.set_used(frame, true);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame as u64 * PAGE_SIZE,
+        )))
+    }
+
+    /// Return `count` frames from `frame`, allocated together
+    pub fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let first = (frame.start_address().as_u64() / PAGE_SIZE) as usize;
+        for frame in first..first + count {
+            assert!(
+                self.is_used(frame),
+                "frame {:#x} freed twice",
+                frame as u64 * PAGE_SIZE
+            );
+            self.set_used(frame, false);
+        }
+    }
+
+    pub fn deallocate(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1);
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn usable_frames(&self) -> usize {
+        self.usable
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Take over the usable memory regions, physical memory must be mapped at
+/// `offset`
+pub fn init(regions: &[MemoryRegion], offset: usize) {
+    FRAMES.call_once(|| Mutex::new(unsafe { BitmapFrameAllocator::new(regions, offset) }));
+}
+
+fn with_frames<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R, {
+    let frames = FRAMES.get().expect("Frame allocator is not initialized.");
+    without_interrupts(|| f(&mut frames.lock()))
+}
+
+pub fn allocate() -> Option<PhysFrame> {
+    with_frames(|frames| frames.allocate())
+}
+
+/// See [`BitmapFrameAllocator::allocate_contiguous`]
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrame> {
+    with_frames(|frames| frames.allocate_contiguous(count, align))
+}
+
+/// See [`BitmapFrameAllocator::allocate_below`]
+pub fn allocate_below(limit: PhysAddr) -> Option<PhysFrame> {
+    with_frames(|frames| frames.allocate_below(limit))
+}
+
+pub fn deallocate(frame: PhysFrame) {
+    with_frames(|frames| frames.deallocate(frame))
+}
+
+pub fn deallocate_contiguous(frame: PhysFrame, count: usize) {
+    with_frames(|frames| frames.deallocate_contiguous(frame, count))
+}
+
+/// End of the last usable region, there is no memory to allocate above it
+pub fn memory_end() -> PhysAddr {
+    with_frames(|frames| PhysAddr::new(frames.frames() as u64 * PAGE_SIZE))
+}
+
+/// Free and usable frames
+pub fn usage() -> (usize, usize) {
+    with_frames(|frames| (frames.free_frames(), frames.usable_frames()))
+}
+
+/// Frame source for page tables created by [`paging`](super::paging)
+pub struct KernelFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate()
+    }
+}
+
+#[test_case]
+fn test_contiguous_frames() {
+    let (free, _) = usage();
+    let frame = allocate_contiguous(4, 4).expect("no contiguous frames");
+
+    assert_eq!(frame.start_address().as_u64() % (4 * PAGE_SIZE), 0);
+    assert_eq!(usage().0, free - 4);
+    deallocate_contiguous(frame, 4);
+    assert_eq!(usage().0, free);
+}
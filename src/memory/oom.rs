@@ -0,0 +1,244 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::alloc::Layout;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::paging::PAGE_SIZE;
+use super::{accounting, frames, heap_size, shrink_caches, slab};
+use crate::platform::smp::current_cpu;
+use crate::prelude::*;
+use crate::wasm::{sip, SipId};
+
+/// Kept from the heap until a SIP is killed, so the kernel can make progress
+/// until the executor drops the SIP and its memory
+static RECOVERY_RESERVE: Reserve = Reserve::new(1024 * 1024);
+/// Kept for logging the memory report before the kernel panics
+static REPORT_RESERVE: Reserve = Reserve::new(256 * 1024);
+
+/// Core reclaiming memory plus one, zero while none is. Allocations failing on
+/// other cores meanwhile are retried once it is done.
+static RECLAIMER: AtomicUsize = AtomicUsize::new(0);
+
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::KillLargest as u8);
+
+/// What to do once shrinking the caches doesn't make an allocation succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    /// Kill the SIP with the most heap memory allocated
+    KillLargest,
+    /// Kill the SIP making the failing allocation, or the largest one if the
+    /// kernel is making it
+    KillAllocating,
+    /// Never kill a SIP, panic instead
+    Panic,
+}
+
+impl OomPolicy {
+    fn from_u8(value: u8) -> OomPolicy {
+        match value {
+            0 => OomPolicy::KillLargest,
+            1 => OomPolicy::KillAllocating,
+            _ => OomPolicy::Panic,
+        }
+    }
+}
+
+/// Heap block set aside. It is freed when the heap runs out and taken from
+/// the heap again once there is room, so it works for every OOM, not only the
+/// first one.
+struct Reserve {
+    block: Mutex<Option<usize>>,
+    size: usize,
+}
+
+impl Reserve {
+    const fn new(size: usize) -> Self {
+        Self {
+            block: Mutex::new(None),
+            size,
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size, PAGE_SIZE as usize).expect("Invalid reserve size.")
+    }
+
+    /// Set the block aside again if it was freed
+    fn fill(&self) -> bool {
+        let mut block = self.block.lock();
+        if block.is_none() {
+            *block = super::reserve_block(self.layout());
+        }
+        block.is_some()
+    }
+
+    /// Free the block, `false` if it already was
+    fn release(&self) -> bool {
+        match self.block.lock().take() {
+            Some(block) => {
+                unsafe { super::release_block(block, self.layout()) };
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+pub fn policy() -> OomPolicy {
+    OomPolicy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+pub fn set_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub(super) fn init() {
+    if !RECOVERY_RESERVE.fill() || !REPORT_RESERVE.fill() {
+        panic!("Not enough memory for the out of memory reserves.");
+    }
+}
+
+/// Set the recovery reserve aside again once a killed SIP freed its memory.
+/// Returns `false` if the heap has no room for it yet.
+pub fn replenish() -> bool {
+    RECOVERY_RESERVE.fill()
+}
+
+/// Make room after an allocation of `layout` failed, first by shrinking the
+/// caches, then by killing a SIP as the [policy](OomPolicy) says. Returns
+/// whether the allocation is worth retrying.
+pub(super) fn reclaim(layout: Layout) -> bool {
+    let cpu = current_cpu() as usize + 1;
+    match RECLAIMER.compare_exchange(0, cpu, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => {},
+        // Allocating while reclaiming, e.g. to wake the killed SIP
+        Err(reclaimer) if reclaimer == cpu => return false,
+        Err(_) => {
+            while RECLAIMER.load(Ordering::Acquire) != 0 {
+                spin_loop();
+            }
+            return true;
+        },
+    }
+
+    let shrunk = shrink_caches() > 0;
+    let killed = if shrunk { None } else { kill_victim(layout) };
+    RECLAIMER.store(0, Ordering::Release);
+
+    // Logging takes a lock a core waiting for the reclaim may hold
+    if let Some(victim) = killed {
+        warn!("out of memory, killed SIP {:?}", victim);
+    }
+    shrunk || killed.is_some()
+}
+
+fn victim() -> Option<SipId> {
+    match policy() {
+        OomPolicy::KillLargest => accounting::largest_sip(),
+        OomPolicy::KillAllocating => accounting::current_sip().or_else(accounting::largest_sip),
+        OomPolicy::Panic => None,
+    }
+}
+
+/// Kill a SIP and free the recovery reserve. Allocations larger than the
+/// reserve can't succeed until the SIP is dropped, so no SIP is killed for
+/// them. SIPs grow their memory through [`can_allocate`](super::can_allocate)
+/// instead, which fails without killing anything.
+fn kill_victim(layout: Layout) -> Option<SipId> {
+    if layout.size() > RECOVERY_RESERVE.size {
+        return None;
+    }
+    let victim = victim()?;
+
+    // The SIP only frees its memory once the executor drops it, the kernel
+    // runs on the reserve until then. Killing it allocates to wake it.
+    if !RECOVERY_RESERVE.release() {
+        return None;
+    }
+    sip::try_kill(victim);
+    Some(victim)
+}
+
+/// Log where the memory went
+pub fn report() {
+    let (free, usable) = frames::usage();
+    error!(
+        "heap of {} KiB, {} of {} frames free",
+        heap_size() / 1024,
+        free,
+        usable
+    );
+    accounting::dump();
+    slab::dump();
+    #[cfg(feature = "alloc-tracking")]
+    accounting::tracking::dump(16);
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    // Logging allocates too
+    REPORT_RESERVE.release();
+    report();
+
+    panic!(
+        "out of memory allocating {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+}
+
+#[test_case]
+fn test_oom_victim() {
+    use super::accounting::{with_tag, Tag};
+
+    let sip = SipId::new();
+    set_policy(OomPolicy::KillAllocating);
+    let allocating = with_tag(Tag::Sip(sip), victim);
+    set_policy(OomPolicy::Panic);
+    let none = with_tag(Tag::Sip(sip), victim);
+    set_policy(OomPolicy::KillLargest);
+    accounting::release(sip);
+
+    assert_eq!(allocating, Some(sip));
+    assert_eq!(none, None);
+}
+
+#[test_case]
+fn test_reserve_refilled_from_heap() {
+    let reserve = Reserve::new(64 * 1024);
+    assert!(reserve.fill());
+    assert!(reserve.release());
+    assert!(!reserve.release());
+    assert!(reserve.fill());
+    assert!(reserve.release());
+}
+
+#[test_case]
+fn test_large_allocation_kills_nothing() {
+    let layout = Layout::from_size_align(2 * RECOVERY_RESERVE.size, 1).unwrap();
+    assert_eq!(kill_victim(layout), None);
+}
@@ -0,0 +1,464 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MapperFlush, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{frames, physical_memory_offset};
+use crate::platform::interrupts::without_interrupts;
+use crate::platform::smp::{self, current_cpu};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Local APIC IDs fit in a byte
+const MAX_CPUS: usize = 256;
+
+/// Mapper of the active page table, which all cores share. Every change is
+/// flushed from the TLBs of all cores before the lock is released.
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+
+/// Cores that have yet to flush their TLB for the running shootdown. The
+/// mapper lock is held during a shootdown, so only one runs at a time.
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_PENDING: [AtomicBool; MAX_CPUS] = [NOT_PENDING; MAX_CPUS];
+static SHOOTDOWN_REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Build the mapper over the active level 4 table
+///
+/// # Safety
+/// All physical memory must be mapped at `physical_memory_offset`, and this
+/// must be called only once.
+pub unsafe fn init(physical_memory_offset: usize) {
+    let offset = VirtAddr::new(physical_memory_offset as u64);
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = &mut *(offset + level_4_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+
+    MAPPER.call_once(|| Mutex::new(OffsetPageTable::new(level_4_table, offset)));
+
+    // Without it the no-execute bit is reserved, and faults instead of
+    // protecting
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> R, {
+    let mapper = MAPPER.get().expect("Paging is not initialized.");
+    // Page faults and other interrupt handlers may translate addresses
+    without_interrupts(|| f(&mut lock(mapper)))
+}
+
+/// Take the mapper lock with interrupts disabled. The core holding it may wait
+/// for this one to flush its TLB, so that is done while spinning.
+fn lock(mapper: &Mutex<OffsetPageTable<'static>>) -> MutexGuard<'_, OffsetPageTable<'static>> {
+    loop {
+        if let Some(guard) = mapper.try_lock() {
+            return guard;
+        }
+        flush_if_requested();
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush the TLBs of the other cores and wait until they are done. Must be
+/// called with the mapper lock held and interrupts disabled.
+///
+/// Callers must not hold locks that other cores take with interrupts
+/// disabled, those cores couldn't acknowledge the shootdown.
+fn shootdown() {
+    let current = current_cpu();
+    for cpu in smp::online_cpus().filter(|&cpu| cpu != current) {
+        SHOOTDOWN_REMAINING.fetch_add(1, Ordering::AcqRel);
+        SHOOTDOWN_PENDING[cpu as usize % MAX_CPUS].store(true, Ordering::Release);
+        smp::send_tlb_shootdown(cpu);
+    }
+
+    while SHOOTDOWN_REMAINING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Handler of the shootdown interrupt sent by [`shootdown`]
+pub fn on_shootdown_interrupt() {
+    flush_if_requested();
+}
+
+fn flush_if_requested() {
+    if SHOOTDOWN_PENDING[current_cpu() as usize % MAX_CPUS].swap(false, Ordering::AcqRel) {
+        tlb::flush_all();
+        SHOOTDOWN_REMAINING.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Pages covering `size` bytes from `start`
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::containing_address(start);
+    let count = (start.as_u64() - first.start_address().as_u64() + size + PAGE_SIZE - 1) / PAGE_SIZE;
+    (0..count).map(move |index| first + index)
+}
+
+/// Whether `flags` make pages both writable and executable
+fn is_write_execute(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Reason [`map`] failed
+#[derive(Debug)]
+pub enum MapError {
+    /// The flags are writable without
+    /// [`NO_EXECUTE`](PageTableFlags::NO_EXECUTE)
+    WriteExecute,
+    Map(MapToError<Size4KiB>),
+}
+
+/// Map `size` bytes of virtual memory from `virt` to the physical range from
+/// `phys`. Page tables are taken from `allocator`. Nothing is left mapped if
+/// it fails. Pages are never mapped both writable and executable.
+///
+/// Only 4 KiB pages are mapped. Ranges inside a huge page, such as the
+/// physical memory mapping of the bootloader, fail with `ParentEntryHugePage`.
+///
+/// # Safety
+/// The mapping must not alias memory in use as something else, e.g. the
+/// kernel heap.
+pub unsafe fn map<A>(
+    virt: VirtAddr, phys: PhysAddr, size: u64, flags: PageTableFlags, allocator: &mut A,
+) -> Result<(), MapError>
+where
+    A: FrameAllocator<Size4KiB> + ?Sized, {
+    if is_write_execute(flags) {
+        return Err(MapError::WriteExecute);
+    }
+
+    with_mapper(|mapper| {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+
+        for (index, page) in pages(virt, size).enumerate() {
+            let frame = first_frame + index as u64;
+            match mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, allocator) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    for page in pages(virt, size).take(index) {
+                        if let Ok((_, flush)) = mapper.unmap(page) {
+                            flush.flush();
+                        }
+                    }
+                    shootdown();
+                    return Err(MapError::Map(error));
+                },
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Remove the mappings of `size` bytes from `virt`. The page tables and the
+/// physical memory are kept. Like [`map`], this handles 4 KiB pages only.
+///
+/// # Safety
+/// Nothing may use the range anymore.
+pub unsafe fn unmap(virt: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    with_mapper(|mapper| {
+        let result = pages(virt, size).try_for_each(|page| {
+            let (_, flush) = mapper.unmap(page)?;
+            flush.flush();
+            Ok(())
+        });
+        shootdown();
+        result
+    })
+}
+
+/// Reason [`protect`] failed
+#[derive(Debug)]
+pub enum ProtectError {
+    /// The flags are writable without
+    /// [`NO_EXECUTE`](PageTableFlags::NO_EXECUTE)
+    WriteExecute,
+    Update(FlagUpdateError),
+}
+
+impl From<FlagUpdateError> for ProtectError {
+    fn from(error: FlagUpdateError) -> Self {
+        ProtectError::Update(error)
+    }
+}
+
+/// Replace the flags of the mapped range of `size` bytes from `virt`. Like
+/// [`map`], this handles 4 KiB pages only: huge pages fail with
+/// `ParentEntryHugePage` unless they were split with [`split_huge_pages`].
+/// Pages are never made both writable and executable.
+///
+/// # Safety
+/// Removing permissions from memory in use makes its users fault.
+pub unsafe fn protect(virt: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), ProtectError> {
+    if is_write_execute(flags) {
+        return Err(ProtectError::WriteExecute);
+    }
+
+    with_mapper(|mapper| {
+        let result = pages(virt, size).try_for_each(|page| {
+            let flush: MapperFlush<Size4KiB> = mapper.update_flags(page, flags | PageTableFlags::PRESENT)?;
+            flush.flush();
+            Ok(())
+        });
+        shootdown();
+        result
+    })
+}
+
+/// Map the huge pages covering `size` bytes from `virt` with 4 KiB pages
+/// instead, keeping their frames and flags, so [`protect`] and [`unmap`] can
+/// change part of them. Page tables are taken from the frame allocator.
+///
+/// Huge pages using the page attribute table lose their PAT bit.
+pub fn split_huge_pages(virt: VirtAddr, size: u64) -> Result<(), MapToError<Size4KiB>> {
+    const LEVEL_3_PAGE: u64 = 1 << 30;
+    const LEVEL_2_PAGE: u64 = 1 << 21;
+
+    with_mapper(|mapper| {
+        let mut split = false;
+        let mut result = Ok(());
+
+        'pages: for page in pages(virt, size) {
+            let addr = page.start_address();
+            let mut table: &mut PageTable = mapper.level_4_table();
+
+            for &(level, index) in &[(4, addr.p4_index()), (3, addr.p3_index()), (2, addr.p2_index())] {
+                let entry = &mut table[index];
+                let flags = entry.flags();
+                if !flags.contains(PageTableFlags::PRESENT) {
+                    continue 'pages;
+                }
+
+                if flags.contains(PageTableFlags::HUGE_PAGE) {
+                    let frame = match frames::allocate() {
+                        Some(frame) => frame,
+                        None => {
+                            result = Err(MapToError::FrameAllocationFailed);
+                            break 'pages;
+                        },
+                    };
+                    // 1 GiB pages become 2 MiB pages, which are split next
+                    let (page_size, child_flags) = match level {
+                        3 => (LEVEL_3_PAGE, flags),
+                        _ => (LEVEL_2_PAGE, flags - PageTableFlags::HUGE_PAGE),
+                    };
+                    let child_size = page_size / 512;
+                    let start = entry.addr().as_u64() & !(page_size - 1);
+
+                    let children = unsafe { table_at(frame) };
+                    for (index, child) in children.iter_mut().enumerate() {
+                        child.set_addr(PhysAddr::new(start + index as u64 * child_size), child_flags);
+                    }
+                    entry.set_frame(frame, flags - PageTableFlags::HUGE_PAGE - PageTableFlags::GLOBAL);
+                    split = true;
+                }
+                table = unsafe { next_table(entry) };
+            }
+        }
+
+        if split {
+            tlb::flush_all();
+            shootdown();
+        }
+        result
+    })
+}
+
+/// Reserve a higher half region of 512 GiB for the caller, which maps pages in
+/// it with [`map`]. Its level 4 entry is created right away, so the region
+/// isn't handed out twice. Regions hold data only, nothing in them is ever
+/// executable.
+pub fn reserve_region() -> Option<VirtAddr> {
+    with_mapper(|mapper| {
+        let table = mapper.level_4_table();
+        let index = (256..512).find(|&index| table[index].is_unused())?;
+
+        let frame = frames::allocate()?;
+        unsafe { table_at(frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        table[index].set_frame(frame, flags);
+
+        Some(VirtAddr::new(0xFFFF_0000_0000_0000 | (index as u64) << 39))
+    })
+}
+
+/// Physical address and flags of the page `addr` is mapped to
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, offset, flags } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    })
+}
+
+/// Empty table in `frame`
+///
+/// # Safety
+/// The frame must be unused
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let table = VirtAddr::new(frame.start_address().as_u64() + physical_memory_offset() as u64);
+    let table = &mut *table.as_mut_ptr::<PageTable>();
+    table.zero();
+    table
+}
+
+/// Table an entry of a higher level table points to
+///
+/// # Safety
+/// The entry must be present and not map a huge page
+unsafe fn next_table(entry: &PageTableEntry) -> &'static mut PageTable {
+    let addr = entry.addr().as_u64() + physical_memory_offset() as u64;
+    &mut *VirtAddr::new(addr).as_mut_ptr::<PageTable>()
+}
+
+/// Flags of the page `addr` is mapped to as the CPU enforces them, combining
+/// the entries of all levels. A page is writable only if all of them allow
+/// it, and not executable if any of them forbids it.
+pub fn effective_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+
+    with_mapper(|mapper| {
+        let mut table: &PageTable = mapper.level_4_table();
+        let mut writable = true;
+        let mut no_execute = false;
+
+        for (level, &index) in indexes.iter().enumerate() {
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            writable &= flags.contains(PageTableFlags::WRITABLE);
+            no_execute |= flags.contains(PageTableFlags::NO_EXECUTE);
+
+            if level == indexes.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                let mut flags = flags;
+                flags.set(PageTableFlags::WRITABLE, writable);
+                flags.set(PageTableFlags::NO_EXECUTE, no_execute);
+                return Some(flags);
+            }
+            table = unsafe { next_table(entry) };
+        }
+        None
+    })
+}
+
+/// Make every writable page not executable, returning the number of pages or
+/// huge pages changed
+///
+/// # Safety
+/// No code may run from writable pages
+pub unsafe fn enforce_write_xor_execute() -> usize {
+    unsafe fn enforce(table: &mut PageTable, level: usize, writable: bool, no_execute: bool) -> usize {
+        let mut changed = 0;
+
+        for entry in table.iter_mut() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let writable = writable && flags.contains(PageTableFlags::WRITABLE);
+            let no_execute = no_execute || flags.contains(PageTableFlags::NO_EXECUTE);
+
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                if writable && !no_execute {
+                    entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+                    changed += 1;
+                }
+            } else {
+                changed += enforce(next_table(entry), level - 1, writable, no_execute);
+            }
+        }
+        changed
+    }
+
+    with_mapper(|mapper| {
+        let changed = enforce(mapper.level_4_table(), 4, true, false);
+        tlb::flush_all();
+        shootdown();
+        changed
+    })
+}
+
+#[test_case]
+fn test_translate_heap() {
+    use alloc::boxed::Box;
+
+    let value = Box::new(42u64);
+    let addr = VirtAddr::from_ptr(&*value);
+    let (phys, flags) = translate(addr).expect("heap is not mapped");
+
+    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    assert_eq!(
+        phys.as_u64() + crate::memory::physical_memory_offset() as u64,
+        addr.as_u64()
+    );
+}
+
+#[test_case]
+fn test_shootdown_acknowledged() {
+    let pending = &SHOOTDOWN_PENDING[current_cpu() as usize % MAX_CPUS];
+    SHOOTDOWN_REMAINING.fetch_add(1, Ordering::AcqRel);
+    pending.store(true, Ordering::Release);
+
+    on_shootdown_interrupt();
+    // An interrupt arriving after the flush was done while spinning is ignored
+    on_shootdown_interrupt();
+    assert!(!pending.load(Ordering::Acquire));
+    assert_eq!(SHOOTDOWN_REMAINING.load(Ordering::Acquire), 0);
+}
+
+#[test_case]
+fn test_protect_rejects_write_execute() {
+    use alloc::boxed::Box;
+
+    let value = Box::new(0u64);
+    let result = unsafe { protect(VirtAddr::from_ptr(&*value), 8, PageTableFlags::WRITABLE) };
+    assert!(matches!(result, Err(ProtectError::WriteExecute)));
+}
+
+#[test_case]
+fn test_map_rejects_write_execute() {
+    use alloc::boxed::Box;
+
+    use super::frames::KernelFrameAllocator;
+
+    // Rejected before the existing mapping is looked at
+    let value = Box::new(0u64);
+    let virt = VirtAddr::from_ptr(&*value);
+    let (phys, _) = translate(virt).expect("heap is not mapped");
+    let result = unsafe { map(virt, phys, 8, PageTableFlags::WRITABLE, &mut KernelFrameAllocator) };
+    assert!(matches!(result, Err(MapError::WriteExecute)));
+}
@@ -0,0 +1,216 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::boxed::Box;
+use core::sync::atomic::AtomicU8;
+use core::{mem, slice};
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::paging::{self, PAGE_SIZE};
+use super::physical_memory_offset;
+use crate::prelude::*;
+
+extern "C" {
+    /// ELF header of the kernel image, defined by the linker as it is loaded
+    /// along with the image
+    static __ehdr_start: u8;
+}
+
+/// Offsets of the program header table, its entry size and entry count in
+/// the ELF header
+const E_PHOFF: usize = 32;
+const E_PHENTSIZE: usize = 54;
+const E_PHNUM: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Placed in the read-only data of the kernel image
+static RODATA_PROBE: [u8; 4] = *b"W^X!";
+/// Placed in the writable data of the kernel image
+static DATA_PROBE: AtomicU8 = AtomicU8::new(0);
+
+/// ELF64 program header, describing a segment of the kernel image
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+/// Kind of memory and the permissions it must have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    /// Read-only and executable
+    Code,
+    /// Read-only and not executable
+    ReadOnly,
+    /// Writable and not executable
+    Data,
+}
+
+impl Expected {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            Expected::Code => PageTableFlags::PRESENT,
+            Expected::ReadOnly => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            Expected::Data => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        }
+    }
+
+    fn is_met_by(self, flags: PageTableFlags) -> bool {
+        let writable = flags.contains(PageTableFlags::WRITABLE);
+        let executable = !flags.contains(PageTableFlags::NO_EXECUTE);
+
+        match self {
+            Expected::Code => !writable && executable,
+            Expected::ReadOnly => !writable && !executable,
+            Expected::Data => writable && !executable,
+        }
+    }
+}
+
+/// Loaded segments of the kernel image, with the permissions they must have.
+/// Sections aren't loaded, but the linker gives code, read-only data and data
+/// segments of their own. The program headers follow the ELF header in the
+/// first segment.
+fn kernel_segments() -> impl Iterator<Item = (VirtAddr, u64, Expected)> {
+    let (base, headers) = unsafe {
+        let elf = &__ehdr_start as *const u8;
+        let offset = elf.add(E_PHOFF).cast::<u64>().read_unaligned() as usize;
+        let entry_size = elf.add(E_PHENTSIZE).cast::<u16>().read_unaligned() as usize;
+        let count = elf.add(E_PHNUM).cast::<u16>().read_unaligned() as usize;
+        assert_eq!(
+            entry_size,
+            mem::size_of::<ProgramHeader>(),
+            "kernel is not a 64-bit ELF"
+        );
+        (
+            elf as u64,
+            slice::from_raw_parts(elf.add(offset).cast::<ProgramHeader>(), count),
+        )
+    };
+    // The segment holding the ELF header is loaded at `base`
+    let bias = headers
+        .iter()
+        .find(|header| header.kind == PT_LOAD && header.offset == 0)
+        .map_or(0, |header| base.wrapping_sub(header.vaddr));
+
+    headers
+        .iter()
+        .filter(|header| header.kind == PT_LOAD && header.memory_size > 0)
+        .map(move |header| {
+            let start = VirtAddr::new(header.vaddr.wrapping_add(bias));
+            let expected = match (header.flags & PF_W != 0, header.flags & PF_X != 0) {
+                (true, true) => panic!("kernel segment at {:?} is writable and executable", start),
+                (true, false) => Expected::Data,
+                (false, true) => Expected::Code,
+                (false, false) => Expected::ReadOnly,
+            };
+            (start, header.memory_size, expected)
+        })
+}
+
+/// Make no memory both writable and executable, and make the kernel honor
+/// read-only pages too. The segments of the kernel image get the permissions
+/// of their ELF flags, so code is only executable and read-only data neither.
+/// Everything else writable, like the heap, stacks and the physical memory
+/// map, is made not executable. WASM linear memory lives on the heap, the
+/// interpreter never executes it either way.
+///
+/// # Safety
+/// No code may run from writable pages
+pub unsafe fn enforce() {
+    for (start, size, expected) in kernel_segments() {
+        if let Err(error) = paging::split_huge_pages(start, size) {
+            panic!("Failed to split the kernel segment at {:?}: {:?}", start, error);
+        }
+        if let Err(error) = paging::protect(start, size, expected.flags()) {
+            panic!("Failed to protect the kernel segment at {:?}: {:?}", start, error);
+        }
+    }
+    paging::enforce_write_xor_execute();
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+}
+
+/// Check the permissions of every kind of kernel memory. Panics if one of
+/// them is wrong.
+pub fn verify() {
+    let stack_probe = 0u8;
+    let heap_probe = Box::new(0u8);
+    let checks = [
+        ("code", VirtAddr::new(verify as usize as u64), Expected::Code),
+        ("rodata", VirtAddr::from_ptr(&RODATA_PROBE), Expected::ReadOnly),
+        ("data", VirtAddr::from_ptr(&DATA_PROBE), Expected::Data),
+        ("heap", VirtAddr::from_ptr(&*heap_probe), Expected::Data),
+        ("stack", VirtAddr::from_ptr(&stack_probe), Expected::Data),
+        (
+            "physical memory map",
+            VirtAddr::new(physical_memory_offset() as u64),
+            Expected::Data,
+        ),
+    ];
+
+    let segment_pages = kernel_segments().flat_map(|(start, size, expected)| {
+        let end = start + size;
+        (start.align_down(PAGE_SIZE).as_u64()..end.as_u64())
+            .step_by(PAGE_SIZE as usize)
+            .map(move |addr| ("kernel segment", VirtAddr::new(addr), expected))
+    });
+
+    for (name, addr, expected) in checks.iter().copied().chain(segment_pages) {
+        let flags =
+            paging::effective_flags(addr).unwrap_or_else(|| panic!("{} at {:?} is not mapped", name, addr));
+        if !expected.is_met_by(flags) {
+            panic!(
+                "{} at {:?} must be {:?}, but is mapped with {:?}",
+                name, addr, expected, flags
+            );
+        }
+    }
+    assert!(
+        Cr0::read().contains(Cr0Flags::WRITE_PROTECT),
+        "the kernel can write to read-only pages"
+    );
+
+    info!("memory protections verified");
+}
+
+#[test_case]
+fn test_kernel_segments_cover_image() {
+    let covers = |addr: VirtAddr, kind: Expected| {
+        kernel_segments()
+            .any(|(start, size, expected)| expected == kind && addr >= start && addr < start + size)
+    };
+
+    assert!(covers(VirtAddr::new(verify as usize as u64), Expected::Code));
+    assert!(covers(VirtAddr::from_ptr(&RODATA_PROBE), Expected::ReadOnly));
+    assert!(covers(VirtAddr::from_ptr(&DATA_PROBE), Expected::Data));
+}
@@ -0,0 +1,383 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{mem, ptr};
+
+use spin::Mutex;
+
+use crate::platform::interrupts::without_interrupts;
+use crate::platform::smp::current_cpu;
+use crate::prelude::*;
+
+/// Object sizes with a cache, allocations are served by the smallest one
+/// that fits
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = SIZE_CLASSES.len();
+/// Larger allocations are served by the heap
+pub(super) const MAX_OBJECT_SIZE: usize = SIZE_CLASSES[CLASS_COUNT - 1];
+
+/// Slabs are taken from the heap aligned to their size, so the slab of an
+/// object is found by masking its address
+pub const SLAB_SIZE: usize = 16 * 1024;
+pub const SLAB_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE) };
+
+/// Objects a core keeps of every class without touching the shared depot
+const MAGAZINE_SIZE: usize = 16;
+/// Cores with magazines of their own, indexed by APIC ID. Cores beyond share
+/// them through their locks.
+const MAX_CPUS: usize = 64;
+
+/// Link of a free object, stored in the object itself
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Start of every slab
+struct SlabHeader {
+    free: *mut FreeObject,
+    free_count: usize,
+    /// Next slab with free objects
+    next: *mut SlabHeader,
+}
+
+fn slab_of(object: *mut u8) -> *mut SlabHeader {
+    (object as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader
+}
+
+/// Space at the start of a slab taken by its header, keeping objects aligned
+/// to their size
+fn header_space(size: usize) -> usize {
+    let header = mem::size_of::<SlabHeader>();
+    (header + size - 1) / size * size
+}
+
+fn objects_per_slab(size: usize) -> usize {
+    (SLAB_SIZE - header_space(size)) / size
+}
+
+/// Objects of a class shared by all cores, in slabs with free objects
+struct Depot {
+    partial: *mut SlabHeader,
+    slabs: usize,
+}
+
+// Only accessed with the lock of its cache held
+unsafe impl Send for Depot {}
+
+impl Depot {
+    const fn new() -> Self {
+        Self {
+            partial: ptr::null_mut(),
+            slabs: 0,
+        }
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut u8> {
+        let slab = self.partial.as_mut()?;
+        let object = slab.free;
+        slab.free = (*object).next;
+        slab.free_count -= 1;
+
+        if slab.free_count == 0 {
+            self.partial = slab.next;
+            slab.next = ptr::null_mut();
+        }
+        Some(object.cast())
+    }
+
+    unsafe fn push(&mut self, object: *mut u8) {
+        let slab = &mut *slab_of(object);
+        let object = object.cast::<FreeObject>();
+        (*object).next = slab.free;
+        slab.free = object;
+        slab.free_count += 1;
+
+        if slab.free_count == 1 {
+            slab.next = self.partial;
+            self.partial = slab;
+        }
+    }
+
+    /// Split a new slab into free objects of `size` bytes
+    unsafe fn add_slab(&mut self, slab: *mut u8, size: usize) {
+        let header = slab.cast::<SlabHeader>();
+        header.write(SlabHeader {
+            free: ptr::null_mut(),
+            free_count: 0,
+            next: ptr::null_mut(),
+        });
+        self.slabs += 1;
+
+        for index in (0..objects_per_slab(size)).rev() {
+            self.push(slab.add(header_space(size) + index * size));
+        }
+    }
+
+    /// Hand every slab without objects in use to `release`
+    unsafe fn shrink(&mut self, size: usize, release: &mut impl FnMut(*mut u8)) -> usize {
+        let mut released = 0;
+        let mut link = &mut self.partial as *mut *mut SlabHeader;
+
+        while let Some(slab) = (*link).as_mut() {
+            if slab.free_count == objects_per_slab(size) {
+                *link = slab.next;
+                release((slab as *mut SlabHeader).cast());
+                released += 1;
+            } else {
+                link = &mut slab.next;
+            }
+        }
+        self.slabs -= released;
+        released
+    }
+
+    /// Free objects in all slabs
+    unsafe fn free_objects(&self) -> usize {
+        let mut count = 0;
+        let mut slab = self.partial;
+        while let Some(header) = slab.as_ref() {
+            count += header.free_count;
+            slab = header.next;
+        }
+        count
+    }
+}
+
+/// Objects of a class cached by a core, taken and returned most recent first
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+// Only accessed with its lock held
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [ptr::null_mut(); MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.count = self.count.checked_sub(1)?;
+        Some(self.objects[self.count])
+    }
+
+    fn push(&mut self, object: *mut u8) {
+        self.objects[self.count] = object;
+        self.count += 1;
+    }
+}
+
+struct Cache {
+    depot: Mutex<Depot>,
+    /// Allocations served from a magazine
+    hits: AtomicU64,
+    /// Allocations that refilled a magazine from the depot
+    misses: AtomicU64,
+}
+
+impl Cache {
+    const fn new() -> Self {
+        Self {
+            depot: Mutex::new(Depot::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+const EMPTY_CACHE: Cache = Cache::new();
+static CACHES: [Cache; CLASS_COUNT] = [EMPTY_CACHE; CLASS_COUNT];
+
+const EMPTY_MAGAZINE: Mutex<Magazine> = Mutex::new(Magazine::new());
+const EMPTY_MAGAZINES: [Mutex<Magazine>; CLASS_COUNT] = [EMPTY_MAGAZINE; CLASS_COUNT];
+static MAGAZINES: [[Mutex<Magazine>; CLASS_COUNT]; MAX_CPUS] = [EMPTY_MAGAZINES; MAX_CPUS];
+
+fn magazine(class: usize) -> &'static Mutex<Magazine> {
+    &MAGAZINES[current_cpu() as usize % MAX_CPUS][class]
+}
+
+/// Class of the cache serving blocks of `layout`, if any
+pub(super) fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// Allocate an object of `class`. Slabs are taken from `new_slab` once the
+/// cache runs out of objects, it returns null if there is no memory left.
+///
+/// # Safety
+/// Interrupts must be disabled. Slabs must be of [`SLAB_LAYOUT`].
+pub(super) unsafe fn alloc(class: usize, mut new_slab: impl FnMut() -> *mut u8) -> *mut u8 {
+    let cache = &CACHES[class];
+    let mut magazine = magazine(class).lock();
+    if let Some(object) = magazine.pop() {
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+        return object;
+    }
+    cache.misses.fetch_add(1, Ordering::Relaxed);
+
+    // Fill only half of the magazine, so frees right after don't have to
+    // flush it
+    let mut depot = cache.depot.lock();
+    while magazine.count < MAGAZINE_SIZE / 2 {
+        match depot.pop() {
+            Some(object) => magazine.push(object),
+            None => {
+                let slab = new_slab();
+                if slab.is_null() {
+                    break;
+                }
+                depot.add_slab(slab, SIZE_CLASSES[class]);
+            },
+        }
+    }
+    magazine.pop().unwrap_or(ptr::null_mut())
+}
+
+/// Return an object to the cache of `class`
+///
+/// # Safety
+/// Interrupts must be disabled. `object` must be allocated from the same
+/// class.
+pub(super) unsafe fn dealloc(class: usize, object: *mut u8) {
+    let mut magazine = magazine(class).lock();
+    if magazine.count == MAGAZINE_SIZE {
+        let mut depot = CACHES[class].depot.lock();
+        while magazine.count > MAGAZINE_SIZE / 2 {
+            if let Some(object) = magazine.pop() {
+                depot.push(object);
+            }
+        }
+    }
+    magazine.push(object);
+}
+
+/// Return the objects of all magazines to the depots and hand every slab
+/// without objects in use to `release`. Returns the bytes released.
+///
+/// # Safety
+/// Interrupts must be disabled
+pub(super) unsafe fn shrink(mut release: impl FnMut(*mut u8)) -> usize {
+    let mut slabs = 0;
+
+    for (class, cache) in CACHES.iter().enumerate() {
+        for magazines in MAGAZINES.iter() {
+            let mut magazine = magazines[class].lock();
+            let mut depot = cache.depot.lock();
+            while let Some(object) = magazine.pop() {
+                depot.push(object);
+            }
+        }
+        slabs += cache.depot.lock().shrink(SIZE_CLASSES[class], &mut release);
+    }
+    slabs * SLAB_SIZE
+}
+
+/// Statistics of the cache of a size class
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub slabs: usize,
+    /// Free objects in the depot, those in magazines are not counted
+    pub free_objects: usize,
+}
+
+impl CacheStats {
+    /// Share of allocations served from a magazine, in percent
+    pub fn hit_rate(&self) -> u64 {
+        match self.hits + self.misses {
+            0 => 0,
+            total => self.hits * 100 / total,
+        }
+    }
+}
+
+/// Statistics of every cache, smallest objects first
+pub fn stats() -> Vec<CacheStats> {
+    SIZE_CLASSES
+        .iter()
+        .zip(CACHES.iter())
+        .map(|(&object_size, cache)| {
+            let (slabs, free_objects) = without_interrupts(|| {
+                let depot = cache.depot.lock();
+                (depot.slabs, unsafe { depot.free_objects() })
+            });
+            CacheStats {
+                object_size,
+                hits: cache.hits.load(Ordering::Relaxed),
+                misses: cache.misses.load(Ordering::Relaxed),
+                slabs,
+                free_objects,
+            }
+        })
+        .collect()
+}
+
+/// Log the statistics of every cache
+pub fn dump() {
+    info!(
+        "{:>6} {:>12} {:>10} {:>5} {:>6} {:>8}",
+        "size", "hits", "misses", "hit%", "slabs", "free"
+    );
+    for cache in stats() {
+        info!(
+            "{:>6} {:>12} {:>10} {:>5} {:>6} {:>8}",
+            cache.object_size,
+            cache.hits,
+            cache.misses,
+            cache.hit_rate(),
+            cache.slabs,
+            cache.free_objects
+        );
+    }
+}
+
+// Freed blocks are quarantined instead of reused with heap debugging
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn test_slab_reuses_objects() {
+    use alloc::boxed::Box;
+
+    let block = super::accounting::block_layout(Layout::new::<[u8; 40]>()).unwrap();
+    let cache = &CACHES[class_of(block).unwrap()];
+
+    // An interrupt handler could take the freed object
+    without_interrupts(|| {
+        let first = Box::new([0u8; 40]);
+        let address = first.as_ptr();
+        drop(first);
+
+        let hits = cache.hits.load(Ordering::Relaxed);
+        let second = Box::new([0u8; 40]);
+        assert_eq!(second.as_ptr(), address);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), hits + 1);
+    });
+}
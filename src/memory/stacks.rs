@@ -0,0 +1,112 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+
+use spin::{Lazy, Mutex};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::frames::{self, KernelFrameAllocator};
+use super::paging::{self, PAGE_SIZE};
+
+/// Stacks are placed one after another in a region of their own, each one
+/// above an unmapped guard page
+static NEXT_STACK: Lazy<Mutex<VirtAddr>> = Lazy::new(|| {
+    let region = paging::reserve_region().expect("No virtual memory left for stacks.");
+    Mutex::new(region)
+});
+
+/// Guard pages, by the stack they protect
+static GUARDS: Mutex<Vec<(VirtAddr, &'static str)>> = Mutex::new(Vec::new());
+
+/// A kernel stack. Stacks are never freed.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    name: &'static str,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl Stack {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Initial stack pointer, stacks grow down
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+}
+
+/// Map a stack of at least `size` bytes with a guard page below it. `name` is
+/// reported when the stack overflows into its guard.
+pub fn allocate(name: &'static str, size: usize) -> Stack {
+    let pages = (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+    let size = pages * PAGE_SIZE;
+
+    let guard = {
+        let mut next = NEXT_STACK.lock();
+        let guard = *next;
+        *next += PAGE_SIZE + size;
+        guard
+    };
+    let bottom = guard + PAGE_SIZE;
+
+    let phys = frames::allocate_contiguous(pages as usize, 1)
+        .expect("Not enough memory for a stack.")
+        .start_address();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { paging::map(bottom, phys, size, flags, &mut KernelFrameAllocator) }
+        .expect("Failed to map a stack.");
+
+    GUARDS.lock().push((guard, name));
+    Stack {
+        name,
+        bottom,
+        top: bottom + size,
+    }
+}
+
+/// Name of the stack whose guard page contains `addr`. Called from fault
+/// handlers, so it gives up instead of waiting for the lock.
+pub fn guard_owner(addr: VirtAddr) -> Option<&'static str> {
+    let guards = GUARDS.try_lock()?;
+    guards
+        .iter()
+        .find(|(guard, _)| *guard <= addr && addr < *guard + PAGE_SIZE)
+        .map(|(_, name)| *name)
+}
+
+#[test_case]
+fn test_stack_guard() {
+    let stack = allocate("test stack", 4096 * 2);
+    unsafe { (stack.top() - 8u64).as_mut_ptr::<u64>().write(42) };
+
+    assert_eq!(guard_owner(stack.bottom() - 1u64), Some("test stack"));
+    assert_eq!(guard_owner(stack.bottom()), None);
+    assert!(paging::translate(stack.bottom() - 1u64).is_none());
+}
@@ -26,12 +26,28 @@ pub unsafe fn init() {
     arch::x86_64::init();
 }
 
+/// Initialize an application processor started by [`smp::start_cpus`]
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub unsafe fn init_ap() {
+    arch::x86_64::init_ap();
+}
+
 #[cfg(target_arch = "x86_64")]
 #[inline(always)]
 pub unsafe fn pre_init() {
     arch::x86_64::pre_init();
 }
 
+/// Continue execution in `entry` on the stack ending at `top`
+///
+/// This function is unsafe because the current stack is abandoned
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub unsafe fn switch_stack(top: x86_64::VirtAddr, entry: extern "C" fn() -> !) -> ! {
+    arch::x86_64::switch_stack(top, entry);
+}
+
 mod arch;
 
 pub mod datetime;
@@ -41,3 +57,5 @@ pub mod halt;
 pub mod interrupts;
 pub mod power;
 pub mod random;
+pub mod smp;
+pub mod time;
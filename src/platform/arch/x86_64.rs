@@ -21,6 +21,7 @@
 // SOFTWARE.
 
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::VirtAddr;
 
 use self::registers::control::{Xcr0, Xcr0Flags};
 
@@ -41,6 +42,7 @@ unsafe fn enable_simd() {
 }
 
 pub unsafe fn pre_init() {
+    crate::platform::smp::init_current_cpu();
     enable_simd();
 }
 
@@ -51,7 +53,44 @@ pub unsafe fn init() {
 
     gdt::init();
     interrupts::init_idt();
+    pic::init();
+    pit::init(crate::platform::time::TIMER_FREQUENCY as u32);
+    // Calibrate while nothing else runs, rather than on the first task poll
+    crate::platform::time::timestamp_frequency();
+    pic::unmask(0);
     apic::init();
+    crate::platform::smp::set_online();
+
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Initialize an application processor, after [`ap::start`] brought it up.
+/// The PIC and its timer stay with the bootstrap processor.
+pub unsafe fn init_ap() {
+    crate::platform::smp::init_current_cpu();
+    enable_simd();
+    gdt::init();
+    interrupts::init_idt();
+    apic::init();
+    crate::platform::smp::set_online();
+    ap::signal_started();
+
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Continue execution in `entry` on the stack ending at `top`. The current
+/// stack is abandoned.
+///
+/// This function is unsafe because nothing on the current stack may be used
+/// afterwards
+pub unsafe fn switch_stack(top: VirtAddr, entry: extern "C" fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {0}",
+        "call {1}",
+        in(reg) top.as_u64(),
+        in(reg) entry,
+        options(noreturn)
+    )
 }
 
 /// Log implementation using qemu with a uart 1660 serial port
@@ -78,9 +117,12 @@ pub fn test_logger_callback() {
     }
 }
 
+pub mod ap;
 pub mod apic;
 pub mod date;
 pub mod gdt;
 pub mod interrupts;
+pub mod pic;
+pub mod pit;
 pub mod random;
 pub mod registers;
@@ -0,0 +1,239 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::apic;
+use crate::memory::frames::{self, KernelFrameAllocator};
+use crate::memory::paging::{self, PAGE_SIZE};
+use crate::memory::{physical_memory_offset, stacks};
+use crate::platform::time::ticks;
+use crate::prelude::*;
+
+const STACK_SIZE: usize = 1024 * 1024;
+/// Timer ticks to wait for a core to come up
+const STARTUP_TIMEOUT: u64 = 100;
+
+/// Set by a starting core once it doesn't need the trampoline anymore
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// Startup code of the application processors. They start in real mode at the
+// start of the page the trampoline is copied to, and switch straight to long
+// mode with the kernel page table. The page is identity mapped while cores
+// start, and the fields after the code are filled in for every core.
+global_asm!(
+    r#"
+    .intel_syntax noprefix
+    .pushsection .text.ap_trampoline, "ax"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    // PAE, SSE and SSE exceptions
+    mov eax, cr4
+    or eax, (1 << 5) | (1 << 9) | (1 << 10)
+    mov cr4, eax
+    mov eax, dword ptr [ap_trampoline_cr3_offset]
+    mov cr3, eax
+
+    // Long mode and no-execute
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Protection, paging, write protection and the FPU, with caching enabled
+    mov eax, 0x80010033
+    mov cr0, eax
+
+    lgdt [ap_trampoline_gdt_pointer_offset]
+    // jmp 0x08:ap_trampoline_long_mode, the target is filled in at run time
+    .byte 0x66, 0xEA
+    .global ap_trampoline_jump
+ap_trampoline_jump:
+    .long 0
+    .word 0x08
+
+    .code64
+    .global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, qword ptr [rip + ap_trampoline_stack]
+    mov rax, qword ptr [rip + ap_trampoline_entry]
+    call rax
+
+    .align 8
+    .global ap_trampoline_gdt
+ap_trampoline_gdt:
+    .quad 0
+    // 64-bit kernel code segment, accessed already: loading CS would set the
+    // bit otherwise, and the trampoline page is read-only
+    .quad 0x00AF9B000000FFFF
+ap_trampoline_gdt_pointer:
+    .word 15
+    .global ap_trampoline_gdt_base
+ap_trampoline_gdt_base:
+    .long 0
+
+    .align 8
+    .global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+
+    .set ap_trampoline_cr3_offset, ap_trampoline_cr3 - ap_trampoline_start
+    .set ap_trampoline_gdt_pointer_offset, ap_trampoline_gdt_pointer - ap_trampoline_start
+
+    .popsection
+    .att_syntax prefix
+    "#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_jump: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdt_base: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Offset of a trampoline symbol from its start
+fn offset(symbol: &'static u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &ap_trampoline_start } as *const u8 as usize
+}
+
+/// Busy wait for at least `count` timer ticks
+fn wait_ticks(count: u64) {
+    let end = ticks() + count + 1;
+    while ticks() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Called by a starting core once it runs on its own stack with its own
+/// descriptors, so the next core can be started
+pub fn signal_started() {
+    STARTED.store(true, Ordering::Release);
+}
+
+/// Start the application processors with the local APIC IDs `apic_ids` one
+/// after another. Each of them continues in `entry` on a stack of its own and
+/// has to call [`signal_started`]. Returns the number of cores that came up.
+///
+/// # Safety
+/// Must be called once, from the bootstrap processor with interrupts enabled,
+/// as the timer measures the startup delays.
+pub unsafe fn start(apic_ids: &[u32], entry: extern "C" fn() -> !) -> usize {
+    if apic_ids.is_empty() {
+        return 0;
+    }
+
+    // The table is loaded while still in real mode, with a 32-bit register
+    let (level_4_frame, _) = Cr3::read();
+    let cr3 = level_4_frame.start_address().as_u64();
+    if cr3 > u64::from(u32::MAX) {
+        warn!("Page table above 4 GiB, application processors can't be started");
+        return 0;
+    }
+
+    // Startup IPIs can only point at the first megabyte
+    let frame = match frames::allocate_below(PhysAddr::new(0x10_0000)) {
+        Some(frame) => frame,
+        None => {
+            warn!("No memory below 1 MiB for the application processor trampoline");
+            return 0;
+        },
+    };
+    let phys = frame.start_address();
+    let identity = VirtAddr::new(phys.as_u64());
+    if let Err(error) = paging::map(
+        identity,
+        phys,
+        PAGE_SIZE,
+        PageTableFlags::empty(),
+        &mut KernelFrameAllocator,
+    ) {
+        warn!("Failed to map the application processor trampoline: {:?}", error);
+        frames::deallocate(frame);
+        return 0;
+    }
+
+    // Copy the trampoline through the writable physical memory mapping
+    let page = (phys.as_u64() + physical_memory_offset() as u64) as *mut u8;
+    let size = offset(&ap_trampoline_end);
+    ptr::copy_nonoverlapping(&ap_trampoline_start as *const u8, page, size);
+    let long_mode = phys.as_u64() as u32 + offset(&ap_trampoline_long_mode) as u32;
+    let gdt = phys.as_u64() as u32 + offset(&ap_trampoline_gdt) as u32;
+    ptr::write_unaligned(page.add(offset(&ap_trampoline_jump)) as *mut u32, long_mode);
+    ptr::write_unaligned(page.add(offset(&ap_trampoline_gdt_base)) as *mut u32, gdt);
+    let params = page.add(offset(&ap_trampoline_cr3)) as *mut u64;
+    params.write(cr3);
+
+    let mut started = 0;
+    for &apic_id in apic_ids {
+        let stack = stacks::allocate("application processor stack", STACK_SIZE);
+        params.add(1).write(stack.top().as_u64());
+        params.add(2).write(entry as usize as u64);
+        STARTED.store(false, Ordering::Release);
+
+        apic::send_init(apic_id);
+        wait_ticks(10);
+        for _ in 0..2 {
+            apic::send_startup(apic_id, (phys.as_u64() >> 12) as u8);
+            wait_ticks(1);
+        }
+
+        let deadline = ticks() + STARTUP_TIMEOUT;
+        while !STARTED.load(Ordering::Acquire) && ticks() < deadline {
+            core::hint::spin_loop();
+        }
+        if !STARTED.load(Ordering::Acquire) {
+            // It may still come up later, so the trampoline must stay as it is
+            warn!("Application processor {} didn't start", apic_id);
+            return started;
+        }
+        started += 1;
+    }
+
+    paging::unmap(identity, PAGE_SIZE).expect("Trampoline was unmapped.");
+    frames::deallocate(frame);
+    started
+}
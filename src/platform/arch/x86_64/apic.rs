@@ -20,4 +20,117 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-pub fn init() {}
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::frames::KernelFrameAllocator;
+use crate::memory::paging::{self, PAGE_SIZE};
+
+/// Vector of spurious interrupts, it doesn't need an end of interrupt
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0xF_FFFF_F000;
+
+const EOI_REGISTER: usize = 0xB0;
+const SPURIOUS_REGISTER: usize = 0xF0;
+const ICR_LOW_REGISTER: usize = 0x300;
+const ICR_HIGH_REGISTER: usize = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Virtual address of the local APIC registers, zero until [`init`]
+static BASE: AtomicUsize = AtomicUsize::new(0);
+/// Uncached mapping of the registers, shared by all cores
+static MAPPING: Once<VirtAddr> = Once::new();
+
+/// Enable the local APIC of the executing core. Every core maps its own local
+/// APIC at the same physical address.
+pub fn init() {
+    let mut msr = Msr::new(APIC_BASE_MSR);
+    let value = unsafe { msr.read() };
+    unsafe { msr.write(value | APIC_BASE_ENABLE) };
+
+    let phys = PhysAddr::new(value & APIC_BASE_ADDRESS_MASK);
+    let base = MAPPING.call_once(|| map_registers(phys));
+    BASE.store(base.as_u64() as usize, Ordering::Release);
+
+    unsafe { write(SPURIOUS_REGISTER, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR)) };
+}
+
+/// Local APIC ID of the executing core. It is read through `cpuid`, so it
+/// works before the local APIC is mapped, but it is slow: serializing, and a
+/// VM exit under virtualization. Use
+/// [`current_cpu`](crate::platform::smp::current_cpu) instead.
+#[inline(always)]
+pub fn id() -> u32 {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+    leaf.ebx >> 24
+}
+
+/// Signal the end of an interrupt delivered by the local APIC
+pub fn end_of_interrupt() {
+    unsafe { write(EOI_REGISTER, 0) };
+}
+
+/// Send the interrupt `vector` to the core with the local APIC ID `apic_id`
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    // Fixed delivery to a physical destination
+    send(apic_id, u32::from(vector));
+}
+
+/// Reset the core `apic_id`, it then waits for a startup IPI
+pub fn send_init(apic_id: u32) {
+    send(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Start the core `apic_id` in real mode at the physical address `page << 12`
+pub fn send_startup(apic_id: u32, page: u8) {
+    send(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | u32::from(page));
+}
+
+fn send(apic_id: u32, command: u32) {
+    if BASE.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    crate::platform::interrupts::without_interrupts(|| unsafe {
+        while read(ICR_LOW_REGISTER) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        write(ICR_HIGH_REGISTER, apic_id << 24);
+        // Writing the low half sends
+        write(ICR_LOW_REGISTER, command);
+    });
+}
+
+/// Map the registers with caching disabled, they must not be accessed through
+/// the cacheable physical memory mapping
+fn map_registers(phys: PhysAddr) -> VirtAddr {
+    let virt = paging::reserve_region().expect("No virtual memory left for the local APIC.");
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    unsafe { paging::map(virt, phys, PAGE_SIZE, flags, &mut KernelFrameAllocator) }
+        .expect("Failed to map the local APIC.");
+    virt
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Acquire) + register) as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Acquire) + register) as *mut u32, value);
+}
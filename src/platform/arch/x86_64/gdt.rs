@@ -20,55 +20,62 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use spin::Lazy;
+use alloc::boxed::Box;
+
 use x86_64::instructions::segmentation::set_cs;
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+
+use crate::memory::stacks;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults get a stack of their own, so an overflow of the faulting stack
+/// into its guard page is reported instead of escalating into a double fault
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
 
 struct Descriptors {
     gdt: GlobalDescriptorTable,
     selectors: Selectors,
 }
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
+impl Descriptors {
+    /// Descriptors with a TSS and interrupt stacks of their own. Every core
+    /// needs its own, loading a TSS marks it busy.
+    fn new() -> Descriptors {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stacks::allocate("double fault stack", IST_STACK_SIZE).top();
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            stacks::allocate("page fault stack", IST_STACK_SIZE).top();
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
 
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
 
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        stack_start + STACK_SIZE
-    };
-    tss
-});
-
-static GDT: Lazy<Descriptors> = Lazy::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-
-    Descriptors {
-        gdt,
-        selectors: Selectors {
-            code_selector,
-            tss_selector,
-        },
+        Descriptors {
+            gdt,
+            selectors: Selectors {
+                code_selector,
+                tss_selector,
+            },
+        }
     }
-});
+}
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Load the descriptors of the executing core, once per core
 pub unsafe fn init() {
-    GDT.gdt.load();
+    let descriptors: &'static Descriptors = Box::leak(Box::new(Descriptors::new()));
+    descriptors.gdt.load();
 
-    set_cs(GDT.selectors.code_selector);
-    load_tss(GDT.selectors.tss_selector);
+    set_cs(descriptors.selectors.code_selector);
+    load_tss(descriptors.selectors.tss_selector);
 }
@@ -23,10 +23,40 @@
 use core::panic;
 
 use spin::Lazy;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
+
+use super::apic::{self, SPURIOUS_VECTOR};
+use super::pic::{self, PIC_1_OFFSET};
+use crate::memory::stacks;
 use crate::prelude::*;
 
+/// Interrupt vectors handled by the kernel
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    /// Legacy PIC timer IRQ
+    Timer = PIC_1_OFFSET,
+    /// Inter-processor interrupt that wakes a halted core
+    Wakeup = 0xF0,
+    /// Inter-processor interrupt that makes a core flush its TLB
+    TlbShootdown = 0xF1,
+    Spurious = SPURIOUS_VECTOR,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
@@ -36,12 +66,24 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
             .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
     }
 
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(super::gdt::PAGE_FAULT_IST_INDEX);
+    }
+
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
     idt.divide_error.set_handler_fn(divide_error_handler);
     idt.simd_floating_point
         .set_handler_fn(simd_floating_point_handler);
     idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    for (irq, handler) in (1..).zip(IRQ_HANDLERS.iter()) {
+        idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(*handler);
+    }
+    idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_interrupt_handler);
+    idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_interrupt_handler);
+    idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
     idt
 });
 
@@ -68,7 +110,99 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFra
     info!("BREAKPOINT:\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    crate::tasks::timer::on_timer_interrupt();
+    crate::tasks::irq::on_interrupt(0);
+    crate::tasks::watchdog::on_timer_interrupt(stack_frame.instruction_pointer.as_u64());
+    pic::end_of_interrupt(InterruptIndex::Timer.as_u8());
+}
+
+/// Handler of a device IRQ. It only acknowledges the interrupt and wakes the
+/// driver task waiting for it, which does the actual work.
+macro_rules! irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            if pic::is_spurious($irq) {
+                if $irq >= 8 {
+                    pic::end_of_interrupt(PIC_1_OFFSET + 2);
+                }
+                return;
+            }
+
+            // Level-triggered lines keep firing until the driver serviced the
+            // device, so the line stays masked until the driver waits again
+            pic::mask($irq);
+            crate::tasks::irq::on_interrupt($irq);
+            pic::end_of_interrupt(PIC_1_OFFSET + $irq);
+        }
+    };
+}
+
+irq_handler!(irq1_handler, 1);
+irq_handler!(irq2_handler, 2);
+irq_handler!(irq3_handler, 3);
+irq_handler!(irq4_handler, 4);
+irq_handler!(irq5_handler, 5);
+irq_handler!(irq6_handler, 6);
+irq_handler!(irq7_handler, 7);
+irq_handler!(irq8_handler, 8);
+irq_handler!(irq9_handler, 9);
+irq_handler!(irq10_handler, 10);
+irq_handler!(irq11_handler, 11);
+irq_handler!(irq12_handler, 12);
+irq_handler!(irq13_handler, 13);
+irq_handler!(irq14_handler, 14);
+irq_handler!(irq15_handler, 15);
+
+/// Handlers of the IRQs after the timer, in order
+const IRQ_HANDLERS: [HandlerFunc; 15] = [
+    irq1_handler,
+    irq2_handler,
+    irq3_handler,
+    irq4_handler,
+    irq5_handler,
+    irq6_handler,
+    irq7_handler,
+    irq8_handler,
+    irq9_handler,
+    irq10_handler,
+    irq11_handler,
+    irq12_handler,
+    irq13_handler,
+    irq14_handler,
+    irq15_handler,
+];
+
+/// Brings the core out of `hlt` so its executor looks at its run queue again.
+/// The watchdog also uses it to make a core check its own poll.
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    crate::tasks::watchdog::on_wakeup_interrupt(stack_frame.instruction_pointer.as_u64());
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::memory::paging::on_shootdown_interrupt();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
+
+/// Panic if `addr` lies in the guard page of a kernel stack
+fn check_stack_overflow(addr: VirtAddr) {
+    if let Some(stack) = stacks::guard_owner(addr) {
+        panic!("stack overflow in {}", stack);
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) -> ! {
+    // A fault while the page fault handler itself overflows ends up here. CR2
+    // may be left from an earlier fault, so the interrupted stack pointer is
+    // checked instead. A faulting push leaves it at the bottom of the stack,
+    // right above the guard page.
+    let stack_pointer = stack_frame.stack_pointer;
+    check_stack_overflow(stack_pointer);
+    check_stack_overflow(stack_pointer - 1u64);
+
     error!("DOUBLE FAULT:\n{:#?}", stack_frame);
     error!("Error Code: {}", error_code);
     panic!()
@@ -77,7 +211,7 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackF
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
+    check_stack_overflow(Cr2::read());
 
     error!("EXCEPTION: PAGE FAULT");
     error!("Accessed Address: {:?}", Cr2::read());
@@ -0,0 +1,172 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// First interrupt vector used by the legacy PICs, right after the CPU
+/// exceptions
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+const CASCADE_IRQ: u8 = 2;
+const CMD_INIT: u8 = 0x11;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0B;
+const MODE_8086: u8 = 0x01;
+
+static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics::new());
+
+/// A 8259 Programmable Interrupt Controller
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    const fn new(offset: u8, command: u16, data: u16) -> Self {
+        Self {
+            offset,
+            command: Port::new(command),
+            data: Port::new(data),
+        }
+    }
+
+    fn handles_interrupt(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
+    }
+
+    /// The In-Service Register, a bit for each line being serviced
+    unsafe fn read_isr(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+}
+
+/// The master and slave PICs of a PC, the slave cascades into IRQ 2
+struct ChainedPics {
+    pics: [Pic; 2],
+}
+
+impl ChainedPics {
+    const fn new() -> Self {
+        Self {
+            pics: [
+                Pic::new(PIC_1_OFFSET, 0x20, 0x21),
+                Pic::new(PIC_2_OFFSET, 0xA0, 0xA1),
+            ],
+        }
+    }
+
+    /// Remap both PICs past the CPU exceptions, leaving every IRQ masked but
+    /// the cascade
+    unsafe fn init(&mut self) {
+        // Writes to an unused port give the PICs time to settle
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || wait_port.write(0);
+
+        for pic in self.pics.iter_mut() {
+            pic.command.write(CMD_INIT);
+            wait();
+        }
+
+        let [master, slave] = &mut self.pics;
+        master.data.write(master.offset);
+        wait();
+        slave.data.write(slave.offset);
+        wait();
+
+        master.data.write(1 << CASCADE_IRQ);
+        wait();
+        slave.data.write(CASCADE_IRQ);
+        wait();
+
+        master.data.write(MODE_8086);
+        wait();
+        slave.data.write(MODE_8086);
+        wait();
+
+        master.data.write(!(1 << CASCADE_IRQ));
+        slave.data.write(0xff);
+    }
+
+    unsafe fn set_masked(&mut self, irq: u8, masked: bool) {
+        let (pic, line) = if irq < 8 {
+            (&mut self.pics[0], irq)
+        } else {
+            (&mut self.pics[1], irq - 8)
+        };
+
+        let mask = pic.data.read();
+        let mask = if masked {
+            mask | (1 << line)
+        } else {
+            mask & !(1 << line)
+        };
+        pic.data.write(mask);
+    }
+
+    /// The PICs raise the lowest priority line, IRQ 7 or 15, when a line drops
+    /// before the CPU acknowledges it. Those interrupts aren't in service.
+    unsafe fn is_spurious(&mut self, irq: u8) -> bool {
+        match irq {
+            7 => self.pics[0].read_isr() & (1 << 7) == 0,
+            15 => self.pics[1].read_isr() & (1 << 7) == 0,
+            _ => false,
+        }
+    }
+
+    unsafe fn end_of_interrupt(&mut self, vector: u8) {
+        if self.pics[1].handles_interrupt(vector) {
+            self.pics[1].command.write(CMD_END_OF_INTERRUPT);
+        }
+        self.pics[0].command.write(CMD_END_OF_INTERRUPT);
+    }
+}
+
+pub unsafe fn init() {
+    PICS.lock().init();
+}
+
+/// Let the PIC deliver `irq`
+pub fn unmask(irq: u8) {
+    crate::platform::interrupts::without_interrupts(|| unsafe { PICS.lock().set_masked(irq, false) });
+}
+
+/// Stop the PIC from delivering `irq`
+pub fn mask(irq: u8) {
+    crate::platform::interrupts::without_interrupts(|| unsafe { PICS.lock().set_masked(irq, true) });
+}
+
+/// Whether the PIC raised `irq` without a device asking for it. A spurious IRQ
+/// 7 must not be acknowledged, a spurious IRQ 15 only on the master, which saw
+/// a real interrupt on the cascade.
+pub fn is_spurious(irq: u8) -> bool {
+    unsafe { PICS.lock().is_spurious(irq) }
+}
+
+/// Acknowledge the interrupt `vector`, must be called at the end of every IRQ
+/// handler
+pub fn end_of_interrupt(vector: u8) {
+    unsafe { PICS.lock().end_of_interrupt(vector) }
+}
@@ -0,0 +1,73 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT oscillator, in Hz
+const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Channel 0, lobyte/hibyte access, rate generator
+const MODE_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 2, lobyte/hibyte access, interrupt on terminal count
+const MODE_ONE_SHOT: u8 = 0b1011_0000;
+
+/// PIT counts used to calibrate, about 10 ms
+const CALIBRATION_COUNT: u16 = 11932;
+
+/// Program the PIT to raise IRQ 0 `frequency` times per second
+pub unsafe fn init(frequency: u32) {
+    let divisor = (BASE_FREQUENCY / frequency) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+
+    command.write(MODE_RATE_GENERATOR);
+    channel0.write(divisor as u8);
+    channel0.write((divisor >> 8) as u8);
+}
+
+/// Measure the timestamp counter frequency, in Hz, against a PIT channel 2
+/// countdown. Busy waits for about 10 ms.
+pub fn tsc_frequency() -> u64 {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel2: Port<u8> = Port::new(0x42);
+
+    unsafe {
+        // Gate low and speaker off while the count is loaded
+        let control = gate.read() & !0b11;
+        gate.write(control);
+        command.write(MODE_ONE_SHOT);
+        channel2.write(CALIBRATION_COUNT as u8);
+        channel2.write((CALIBRATION_COUNT >> 8) as u8);
+
+        // Counting starts with the gate, OUT2 goes high when it reaches zero
+        gate.write(control | 0b1);
+        let start = core::arch::x86_64::_rdtsc();
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = core::arch::x86_64::_rdtsc();
+        gate.write(control);
+
+        (end - start) * u64::from(BASE_FREQUENCY) / u64::from(CALIBRATION_COUNT)
+    }
+}
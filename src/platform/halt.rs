@@ -33,3 +33,21 @@ pub fn permanent_halt() -> ! {
 pub fn temporal_halt() {
     x86_64::instructions::hlt();
 }
+
+/// Halt until the next interrupt, unless `is_idle` returns `false`. The check
+/// runs with interrupts disabled, so a wake-up from an interrupt handler can't
+/// slip in between the check and the halt.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn temporal_halt_if<F>(is_idle: F)
+where
+    F: FnOnce() -> bool, {
+    use x86_64::instructions::interrupts;
+
+    interrupts::disable();
+    if is_idle() {
+        interrupts::enable_and_hlt();
+    } else {
+        interrupts::enable();
+    }
+}
@@ -31,3 +31,9 @@ where
     F: FnOnce() -> R, {
     x86_64::instructions::interrupts::without_interrupts(f)
 }
+
+/// Let the legacy interrupt controller deliver `irq`
+#[cfg(target_arch = "x86_64")]
+pub fn unmask_irq(irq: u8) {
+    super::arch::x86_64::pic::unmask(irq);
+}
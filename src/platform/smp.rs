@@ -0,0 +1,134 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use acpi::platform::{PlatformInfo, ProcessorState};
+use acpi::{AcpiHandler, AcpiTables};
+
+use crate::prelude::*;
+
+/// Local APIC IDs fit in a byte
+const MAX_CPUS: usize = 256;
+
+/// Bitmap of the cores that take interrupts, by APIC ID
+const NONE_ONLINE: AtomicU64 = AtomicU64::new(0);
+static ONLINE: [AtomicU64; MAX_CPUS / 64] = [NONE_ONLINE; MAX_CPUS / 64];
+
+/// APIC ID of every core, by APIC ID. The GS base of a core points at its own
+/// entry.
+const NO_CPU: AtomicU32 = AtomicU32::new(0);
+static CPU_IDS: [AtomicU32; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+
+/// Remember the identifier of the executing core, so [`current_cpu`] reads it
+/// instead of executing the slow `cpuid`. Every core calls this first, before
+/// it allocates or takes interrupts.
+#[cfg(target_arch = "x86_64")]
+pub(super) unsafe fn init_current_cpu() {
+    use x86_64::registers::model_specific::GsBase;
+    use x86_64::VirtAddr;
+
+    let id = super::arch::x86_64::apic::id();
+    let entry = &CPU_IDS[id as usize % MAX_CPUS];
+    entry.store(id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(entry));
+}
+
+/// Identifier of the executing core
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn current_cpu() -> u32 {
+    let id: u32;
+    unsafe { asm!("mov {:e}, gs:[0]", out(reg) id, options(nostack, preserves_flags, readonly)) };
+    id
+}
+
+/// Bring the core `cpu` out of `hlt`. The executing core is awake already,
+/// or halted and about to be woken by the interrupt it handles.
+#[cfg(target_arch = "x86_64")]
+pub fn wake_cpu(cpu: u32) {
+    use super::arch::x86_64::apic;
+    use super::arch::x86_64::interrupts::InterruptIndex;
+
+    if cpu != current_cpu() {
+        apic::send_ipi(cpu, InterruptIndex::Wakeup.as_u8());
+    }
+}
+
+/// Record that the executing core is initialized and handles inter-processor
+/// interrupts
+pub(super) fn set_online() {
+    let cpu = current_cpu() as usize % MAX_CPUS;
+    ONLINE[cpu / 64].fetch_or(1 << (cpu % 64), Ordering::Release);
+}
+
+/// Cores that are initialized, including the executing one
+pub fn online_cpus() -> impl Iterator<Item = u32> {
+    (0..MAX_CPUS as u32).filter(|&cpu| {
+        let cpu = cpu as usize;
+        ONLINE[cpu / 64].load(Ordering::Acquire) & (1 << (cpu % 64)) != 0
+    })
+}
+
+/// Make the core `cpu` flush its TLB, by calling
+/// [`paging::on_shootdown_interrupt`](crate::memory::paging::on_shootdown_interrupt)
+#[cfg(target_arch = "x86_64")]
+pub fn send_tlb_shootdown(cpu: u32) {
+    use super::arch::x86_64::apic;
+    use super::arch::x86_64::interrupts::InterruptIndex;
+
+    apic::send_ipi(cpu, InterruptIndex::TlbShootdown.as_u8());
+}
+
+/// Start the cores other than the executing one, described by the ACPI
+/// tables. Each of them calls [`init_ap`](super::init_ap) and continues in
+/// `entry`. Returns the number of cores started.
+///
+/// # Safety
+/// Must be called once, from the bootstrap processor after
+/// [`init`](super::init)
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn start_cpus<H>(tables: &AcpiTables<H>, entry: extern "C" fn() -> !) -> usize
+where
+    H: AcpiHandler, {
+    use super::arch::x86_64::ap;
+
+    let processors = match tables.platform_info() {
+        Ok(PlatformInfo {
+            processor_info: Some(processors),
+            ..
+        }) => processors,
+        _ => return 0,
+    };
+    let apic_ids: Vec<u32> = processors
+        .application_processors
+        .iter()
+        .filter(|processor| !matches!(processor.state, ProcessorState::Disabled))
+        .map(|processor| u32::from(processor.local_apic_id))
+        .collect();
+    ap::start(&apic_ids, entry)
+}
+
+#[test_case]
+fn test_current_cpu_is_cached_apic_id() {
+    assert_eq!(current_cpu(), super::arch::x86_64::apic::id());
+}
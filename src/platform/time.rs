@@ -0,0 +1,91 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Duration;
+use spin::Lazy;
+
+/// Frequency of the periodic timer interrupt, in Hz
+pub const TIMER_FREQUENCY: u64 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Frequency of [`timestamp`] in Hz, calibrated once against the PIT
+#[cfg(target_arch = "x86_64")]
+static TIMESTAMP_FREQUENCY: Lazy<u64> = Lazy::new(super::arch::x86_64::pit::tsc_frequency);
+
+/// Count a timer interrupt, returns the updated tick count
+#[inline(always)]
+pub fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Timer interrupts since boot
+#[inline(always)]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Monotonic time since the timer was started
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::microseconds((ticks.saturating_mul(1_000_000) / TIMER_FREQUENCY) as i64)
+}
+
+/// Number of ticks that cover at least `duration`
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let micros = duration.num_microseconds().unwrap_or(i64::MAX).max(0) as u64;
+    micros.saturating_mul(TIMER_FREQUENCY).saturating_add(999_999) / 1_000_000
+}
+
+/// Read a monotonic, high resolution timestamp. Its unit is CPU specific, so
+/// it is only useful to order and compare events.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Timestamp increments per second. Calibrating takes about 10 ms, which the
+/// first call pays.
+#[cfg(target_arch = "x86_64")]
+pub fn timestamp_frequency() -> u64 {
+    *TIMESTAMP_FREQUENCY
+}
+
+/// Time covered by `cycles` increments of [`timestamp`]. Assumes an invariant
+/// timestamp counter, running at the same rate on every core.
+pub fn timestamp_to_duration(cycles: u64) -> Duration {
+    let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(timestamp_frequency().max(1));
+    Duration::nanoseconds(nanos.min(i64::MAX as u128) as i64)
+}
+
+#[test_case]
+fn test_duration_to_ticks() {
+    assert_eq!(duration_to_ticks(Duration::zero()), 0);
+    assert_eq!(duration_to_ticks(Duration::seconds(1)), TIMER_FREQUENCY);
+    assert_eq!(duration_to_ticks(Duration::microseconds(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::seconds(-1)), 0);
+}
@@ -21,39 +21,172 @@
 // SOFTWARE.
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use core::future::Future;
+use core::panic::Location;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use core::task::{Context, Poll};
 
+use self::info::TaskInfo;
+use self::join::AbortHandle;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(u64);
 
+/// Scheduling class of a task, from most to least urgent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    /// Latency critical work, e.g. input handling
+    Realtime,
+    /// Interrupt driven driver work
+    Driver,
+    Normal,
+    /// Background work
+    Idle,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 4] = [
+        Priority::Realtime,
+        Priority::Driver,
+        Priority::Normal,
+        Priority::Idle,
+    ];
+
+    /// Number of tasks of this class polled per scheduling round. Every class
+    /// gets some share, so none of them starves.
+    pub fn weight(self) -> usize {
+        match self {
+            Priority::Realtime => 16,
+            Priority::Driver => 8,
+            Priority::Normal => 4,
+            Priority::Idle => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> Priority {
+        Priority::ALL[usize::from(value)]
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Priority of a task, shared with its wakers and handles through its
+/// [`TaskInfo`]. A change applies the next time the task is woken.
+pub struct TaskPriority(AtomicU8);
+
+impl TaskPriority {
+    pub fn new(priority: Priority) -> Self {
+        TaskPriority(AtomicU8::new(priority as u8))
+    }
+
+    pub fn get(&self) -> Priority {
+        Priority::from_u8(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, priority: Priority) {
+        self.0.store(priority as u8, Ordering::Relaxed);
+    }
+}
+
 pub struct Task {
-    id: TaskId,
+    abort: AbortHandle,
+    info: Arc<TaskInfo>,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// The future is `Send`, so the task may move to another core
+    migratable: bool,
 }
 
 impl Task {
+    #[track_caller]
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        let id = TaskId::new();
+        let info = TaskInfo::new(id, None, Priority::default(), Location::caller());
+        Task::with_handles(AbortHandle::new(id), Arc::new(info), future)
+    }
+
+    /// Build a task controlled through `abort` and `info`, which may be shared
+    /// with its [`JoinHandle`](join::JoinHandle)
+    fn with_handles(
+        abort: AbortHandle, info: Arc<TaskInfo>, future: impl Future<Output = ()> + 'static,
+    ) -> Task {
         Task {
-            id: TaskId::new(),
+            abort,
+            info,
             future: Box::pin(future),
+            migratable: false,
+        }
+    }
+
+    /// Like [`Task::with_handles`], for a task idle cores may steal after it
+    /// started
+    fn migratable(
+        abort: AbortHandle, info: Arc<TaskInfo>, future: impl Future<Output = ()> + Send + 'static,
+    ) -> Task {
+        Task {
+            migratable: true,
+            ..Task::with_handles(abort, info, future)
         }
     }
 
+    fn id(&self) -> TaskId {
+        self.abort.id()
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// A task on its way to another core
+pub struct MigratingTask(Task);
+
+// Only tasks built from a `Send` future are wrapped, the rest of a task is
+// shared through `Arc`s already
+unsafe impl Send for MigratingTask {}
+
+impl MigratingTask {
+    /// Wrap `task` if it may move to another core, otherwise hand it back
+    fn new(task: Task) -> Result<MigratingTask, Task> {
+        if task.migratable {
+            Ok(MigratingTask(task))
+        } else {
+            Err(task)
+        }
+    }
+
+    fn into_task(self) -> Task {
+        self.0
+    }
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
 }
 
+pub mod builder;
 pub mod executor;
+pub mod info;
+pub mod irq;
+pub mod join;
 pub mod park;
+pub mod queue;
+pub mod smp;
+pub mod spawner;
+pub mod sync;
+pub mod timer;
 pub mod waker;
+pub mod watchdog;
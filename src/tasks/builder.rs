@@ -0,0 +1,124 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::panic::Location;
+
+use super::executor::TaskExecutor;
+use super::info::TaskInfo;
+use super::join::{self, AbortHandle, JoinHandle};
+use super::spawner::Spawner;
+use super::{Priority, Task, TaskId};
+use crate::memory::accounting::{self, Tag};
+
+/// Configures a task before spawning it. The spawn location recorded for the
+/// task is where the builder was created.
+pub struct Builder {
+    name: Option<String>,
+    priority: Priority,
+    location: &'static Location<'static>,
+}
+
+impl Builder {
+    #[track_caller]
+    pub fn new() -> Self {
+        Builder {
+            name: None,
+            priority: Priority::default(),
+            location: Location::caller(),
+        }
+    }
+
+    /// Name shown in the task table
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawn the future built by `factory` through `spawner`. The factory runs
+    /// on the executor, so the future itself doesn't need to be `Send`.
+    pub fn spawn<F, Fut>(self, spawner: &Spawner, factory: F) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static, {
+        let _tag = accounting::enter(Tag::Tasks);
+        let (abort, info) = self.handles();
+        let (sender, handle) = join::channel(abort.clone(), info.clone());
+
+        spawner.push(Box::new(move || {
+            let future = sender.wrap(factory());
+            accounting::with_tag(Tag::Tasks, || Task::with_handles(abort, info, future))
+        }));
+        handle
+    }
+
+    /// Spawn `future` through `spawner`. Unlike tasks built by a factory, it
+    /// may move to an idle core after it started.
+    pub fn spawn_send<F>(self, spawner: &Spawner, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static, {
+        let _tag = accounting::enter(Tag::Tasks);
+        let (abort, info) = self.handles();
+        let (sender, handle) = join::channel(abort.clone(), info.clone());
+
+        spawner.push(Box::new(move || {
+            let future = sender.wrap(future);
+            accounting::with_tag(Tag::Tasks, || Task::migratable(abort, info, future))
+        }));
+        handle
+    }
+
+    /// Spawn `future` directly on `executor`
+    pub fn spawn_local<F>(self, executor: &mut TaskExecutor, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static, {
+        let _tag = accounting::enter(Tag::Tasks);
+        let (abort, info) = self.handles();
+        let (future, handle) = join::joinable(abort.clone(), info.clone(), future);
+
+        executor.spawn_task(Task::with_handles(abort, info, future));
+        handle
+    }
+
+    fn handles(self) -> (AbortHandle, Arc<TaskInfo>) {
+        let id = TaskId::new();
+        let info = TaskInfo::new(id, self.name, self.priority, self.location);
+        (AbortHandle::new(id), Arc::new(info))
+    }
+}
+
+impl Default for Builder {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
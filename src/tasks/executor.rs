@@ -22,76 +22,441 @@
 
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
-use core::task::{Context, Waker};
+use alloc::vec::Vec;
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 
 use crossbeam_queue::SegQueue;
+use futures::task::noop_waker_ref;
 use futures::Future;
+use rand_chacha::ChaChaRng;
+use rand_core::{RngCore, SeedableRng};
 
+use super::builder::Builder;
+use super::join::JoinHandle;
+use super::queue::ReadyQueues;
+use super::spawner::{SpawnRequest, Spawner};
+use super::timer::VirtualClock;
 use super::waker::TaskWaker;
-use super::{Task, TaskId};
+use super::{info, smp, watchdog, Priority, Task, TaskId};
+use crate::memory::accounting::{self, Tag};
+use crate::platform::smp::current_cpu;
+use crate::platform::time::timestamp;
+use crate::prelude::*;
 
+/// Executor of the tasks of one core
 pub struct TaskExecutor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<SegQueue<TaskId>>,
+    task_queue: Arc<ReadyQueues>,
+    spawn_queue: Arc<SegQueue<SpawnRequest>>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    /// Registered with the other cores, which may take tasks from it
+    shared: bool,
+    test_mode: Option<TestMode>,
+}
+
+/// Makes runs of a [`TaskExecutor`] reproducible
+struct TestMode {
+    seed: u64,
+    rng: ChaChaRng,
+    /// Time of the tasks of this executor
+    clock: Arc<VirtualClock>,
+}
+
+impl TestMode {
+    /// Fisher-Yates shuffle driven by the seeded generator
+    fn shuffle(&mut self, task_ids: &mut [TaskId]) {
+        for i in (1..task_ids.len()).rev() {
+            let j = (self.rng.next_u64() % (i as u64 + 1)) as usize;
+            task_ids.swap(i, j);
+        }
+    }
+}
+
+/// Reported when no task of a deterministic executor can make progress
+struct Deadlock {
+    seed: u64,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadlock, no task can make progress (seed {})", self.seed)
+    }
 }
 
 impl TaskExecutor {
+    /// Create the executor of the executing core
     pub fn new() -> Self {
         TaskExecutor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(SegQueue::new()),
+            task_queue: Arc::new(ReadyQueues::new(current_cpu())),
+            spawn_queue: Arc::new(SegQueue::new()),
             waker_cache: BTreeMap::new(),
+            shared: false,
+            test_mode: None,
         }
     }
 
-    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
-        self.spawn_task(Task::new(future))
+    /// Create an executor for tests. Its tasks see a virtual clock, so their
+    /// sleeps complete as soon as nothing else can run, and ready tasks are
+    /// polled in an order shuffled from `seed`. The seed is logged, a failing
+    /// run is reproduced by passing the same seed again.
+    pub fn deterministic(seed: u64) -> Self {
+        info!("deterministic executor, seed {}", seed);
+
+        let mut executor = Self::new();
+        executor.test_mode = Some(TestMode {
+            seed,
+            rng: ChaChaRng::seed_from_u64(seed),
+            clock: Arc::new(VirtualClock::new()),
+        });
+        executor
+    }
+
+    /// Handle to spawn tasks on this executor from anywhere. Idle cores may
+    /// steal the tasks before this executor picks them up.
+    pub fn spawner(&self) -> Spawner {
+        Spawner::new(self.spawn_queue.clone())
+    }
+
+    /// Spawn a future, its output can be awaited through the returned handle
+    #[track_caller]
+    pub fn spawn<T>(&mut self, future: impl Future<Output = T> + 'static) -> JoinHandle<T>
+    where
+        T: 'static, {
+        self.spawn_with_priority(Priority::default(), future)
+    }
+
+    /// Like [`TaskExecutor::spawn`], scheduling the task as `priority`
+    #[track_caller]
+    pub fn spawn_with_priority<T>(
+        &mut self, priority: Priority, future: impl Future<Output = T> + 'static,
+    ) -> JoinHandle<T>
+    where
+        T: 'static, {
+        Builder::new().priority(priority).spawn_local(self, future)
     }
 
     pub fn spawn_task(&mut self, task: Task) {
-        let task_id = task.id;
+        let _tag = accounting::enter(Tag::Tasks);
+        let task_id = task.id();
+        let priority = task.info.priority.get();
 
-        if self.tasks.insert(task.id, task).is_some() {
+        task.info.set_cpu(self.task_queue.cpu());
+        info::register(&task.info);
+        if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id);
+        self.task_queue.push(task_id, priority);
     }
 
+    /// Change the scheduling class of a task, from its next wake-up on
+    pub fn set_priority(&mut self, task_id: TaskId, priority: Priority) {
+        if let Some(task) = self.tasks.get(&task_id) {
+            task.info.priority.set(priority);
+        }
+    }
+
+    /// Run the tasks of the executing core forever. Every core runs its own
+    /// executor, so spawn requests are shared with the other cores.
     pub fn run(&mut self) -> ! {
+        smp::register(self.task_queue.clone(), self.spawner());
+        self.shared = true;
+
         loop {
+            self.spawn_requested_tasks();
+            if !self.run_ready_tasks() && !self.steal_task() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    /// Run tasks until `future` completes, returning its output. Only tasks of
+    /// this executor run, e.g. a deterministic one in a test.
+    ///
+    /// Panics if no task can make progress anymore in test mode.
+    pub fn block_on<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future + 'static,
+        F::Output: 'static, {
+        let mut handle = self.spawn(future);
+        let mut context = Context::from_waker(noop_waker_ref());
+
+        loop {
+            self.spawn_requested_tasks();
             self.run_ready_tasks();
-            self.sleep_if_idle();
+
+            if let Poll::Ready(output) = Pin::new(&mut handle).poll(&mut context) {
+                return output.expect("Blocked on task was aborted.");
+            }
+            if !self.task_queue.is_empty() || !self.spawn_queue.is_empty() {
+                continue;
+            }
+
+            match &self.test_mode {
+                Some(mode) if !mode.clock.advance() => {
+                    panic!("{}", Deadlock { seed: mode.seed })
+                },
+                Some(_) => {},
+                None => self.task_queue.halt_if_idle(|| self.spawn_queue.is_empty()),
+            }
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        let tasks = &mut self.tasks;
-        let task_queue = &mut self.task_queue;
-        let waker_cache = &mut self.waker_cache;
+    fn spawn_requested_tasks(&mut self) {
+        while let Some(request) = self.spawn_queue.pop() {
+            self.spawn_task(request());
+        }
+    }
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
-            };
+    /// Take a single task or spawn request from elsewhere, so the remaining
+    /// ones are left for other idle cores
+    fn steal_task(&mut self) -> bool {
+        match smp::steal(self.task_queue.cpu()) {
+            Some(task) => {
+                self.spawn_task(task);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Give the ready task `task_id` to idle cores instead of polling it.
+    /// Returns `false` if it has to stay, as its future isn't `Send`.
+    fn offer_task(&mut self, task_id: TaskId) -> bool {
+        let task = match self.tasks.remove(&task_id) {
+            Some(task) => task,
+            None => return false,
+        };
+
+        match smp::offer(self.task_queue.cpu(), task) {
+            Ok(()) => {
+                // Wakers it registered still find it through its info
+                self.waker_cache.remove(&task_id);
+                true
+            },
+            Err(task) => {
+                self.tasks.insert(task_id, task);
+                false
+            },
+        }
+    }
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::task_waker(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+    /// Drop a task and its cached waker, its joiners are woken with a
+    /// cancelled result. Wake-ups still queued for it are ignored.
+    pub fn abort(&mut self, task_id: TaskId) {
+        self.remove_task(task_id);
+    }
+
+    fn remove_task(&mut self, task_id: TaskId) {
+        if self.tasks.remove(&task_id).is_some() {
+            self.waker_cache.remove(&task_id);
+            info::unregister(task_id);
+        }
+    }
+
+    /// Run one weighted round, polling up to [`Priority::weight`] ready tasks
+    /// of every class, most urgent first, so lower classes are delayed but
+    /// never starved. Returns whether any task was polled.
+    ///
+    /// A round is bounded even if tasks keep waking themselves, so the caller
+    /// gets to pick up spawn requests between rounds. Aborted tasks are woken
+    /// and dropped when their wake-up comes up in a round.
+    ///
+    /// While another core is idle and more tasks are queued, one ready task per
+    /// round is offered to it instead of being polled here.
+    fn run_ready_tasks(&mut self) -> bool {
+        if self.test_mode.is_some() {
+            return self.run_shuffled_tasks();
+        }
 
-            if task.poll(&mut context).is_ready() {
-                // task done -> remove it and its cached waker
-                tasks.remove(&task_id);
-                waker_cache.remove(&task_id);
+        let mut offer = self.shared && smp::has_idle_core(self.task_queue.cpu());
+        let mut polled = false;
+        for priority in Priority::ALL.iter().copied() {
+            for _ in 0..priority.weight() {
+                let task_id = match self.task_queue.pop(priority) {
+                    Some(task_id) => task_id,
+                    None => break,
+                };
+                polled = true;
+
+                if offer && !self.task_queue.is_empty() && self.offer_task(task_id) {
+                    offer = false;
+                } else {
+                    self.poll_task(task_id);
+                }
+            }
+        }
+        polled
+    }
+
+    /// Test mode: poll the tasks of the most urgent class that were ready when
+    /// called, in a shuffled order. Returns whether any task was polled.
+    fn run_shuffled_tasks(&mut self) -> bool {
+        let mut batch = Vec::new();
+
+        for priority in Priority::ALL.iter().copied() {
+            while let Some(task_id) = self.task_queue.pop(priority) {
+                batch.push(task_id);
             }
+            if !batch.is_empty() {
+                break;
+            }
+        }
+
+        if let Some(mode) = &mut self.test_mode {
+            mode.shuffle(&mut batch);
         }
+        let polled = !batch.is_empty();
+        for task_id in batch {
+            self.poll_task(task_id);
+        }
+        polled
     }
 
+    fn poll_task(&mut self, task_id: TaskId) {
+        let task = match self.tasks.get_mut(&task_id) {
+            Some(task) => task,
+            // Task finished or was aborted
+            None => return,
+        };
+
+        let task_queue = &self.task_queue;
+        let waker = self.waker_cache.entry(task_id).or_insert_with(|| {
+            accounting::with_tag(Tag::Tasks, || {
+                let waker = TaskWaker::task_waker(task.info.clone(), task_queue.clone());
+                task.abort.register(&waker);
+                waker
+            })
+        });
+
+        // Checked after registering the waker, so an abort can't be missed
+        if task.abort.is_aborted() {
+            self.abort(task_id);
+            return;
+        }
+
+        let mut context = Context::from_waker(waker);
+        #[cfg(feature = "alloc-tracking")]
+        let _site = accounting::tracking::enter_site(task.info.location());
+
+        let _clock = self.test_mode.as_ref().map(|mode| mode.clock.enter());
+
+        task.info.start_poll();
+        let outer_poll = watchdog::poll_started(&task.info);
+        let start = timestamp();
+        let result = task.poll(&mut context);
+        task.info.finish_poll(timestamp().wrapping_sub(start));
+        watchdog::poll_finished(outer_poll);
+
+        if result.is_ready() {
+            // task done -> remove it and its cached waker
+            self.remove_task(task_id);
+        }
+    }
+
+    /// Halt until the next interrupt if there is nothing to run or steal.
+    /// Sleeping tasks are woken by the timer interrupt once their deadline
+    /// passes, wakers and spawners on other cores send an IPI.
     fn sleep_if_idle(&self) {
-        if self.task_queue.is_empty() {
-            crate::platform::halt::temporal_halt();
+        self.task_queue
+            .halt_if_idle(|| self.spawn_queue.is_empty() && smp::nothing_to_steal());
+    }
+}
+
+#[test_case]
+fn test_abort_task() {
+    let mut executor = TaskExecutor::new();
+    let handle = executor.spawn(futures::future::pending::<()>());
+
+    handle.abort();
+    executor.run_ready_tasks();
+    assert!(handle.is_finished());
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_idle_task_not_starved() {
+    use alloc::vec::Vec;
+
+    use spin::Mutex;
+
+    let mut executor = TaskExecutor::new();
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    for _ in 0..32 {
+        let order = order.clone();
+        executor.spawn_with_priority(Priority::Realtime, async move {
+            order.lock().push(Priority::Realtime)
+        });
+    }
+    let idle = order.clone();
+    executor.spawn_with_priority(Priority::Idle, async move { idle.lock().push(Priority::Idle) });
+    executor.run_ready_tasks();
+
+    let order = order.lock();
+    let position = order.iter().position(|priority| *priority == Priority::Idle);
+    assert_eq!(position, Some(Priority::Realtime.weight()));
+}
+
+#[test_case]
+fn test_spawn_not_starved_by_yielding_task() {
+    use crate::tasks::park::yield_now;
+
+    let mut executor = TaskExecutor::new();
+    let spinner = executor.spawn(async {
+        loop {
+            yield_now().await;
         }
+    });
+    let spawner = executor.spawner();
+
+    let value = executor.block_on(async move {
+        let value = spawner.spawn(async { 42 }).await;
+        spinner.abort();
+        assert!(spinner.await.is_err());
+        value
+    });
+    assert_eq!(value, Ok(42));
+}
+
+#[test_case]
+fn test_deterministic_executor() {
+    use spin::Mutex;
+
+    use crate::tasks::park::{now, sleep};
+
+    fn run(seed: u64) -> Vec<u32> {
+        let mut executor = TaskExecutor::deterministic(seed);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for task in 0..8 {
+            let order = order.clone();
+            executor.spawn(async move { order.lock().push(task) });
+        }
+        let slept = executor.block_on(async {
+            let start = now();
+            sleep(chrono::Duration::seconds(60)).await;
+            now() - start
+        });
+        assert!(slept >= chrono::Duration::seconds(60));
+
+        let order = order.lock().clone();
+        order
     }
+
+    let order = run(42);
+    assert_eq!(order, run(42));
+
+    // Every task ran once, in an order that depends on the seed
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+    assert!((0..16).any(|seed| run(seed) != order));
+
+    // A deadlock is reproduced from the seed it reports
+    let message = format!("{}", Deadlock { seed: 42 });
+    assert!(message.starts_with("deadlock"));
+    assert!(message.contains("seed 42"));
 }
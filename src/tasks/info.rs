@@ -0,0 +1,241 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use chrono::Duration;
+use spin::{Lazy, Mutex};
+
+use super::{Priority, TaskId, TaskPriority};
+use crate::platform::time::timestamp_to_duration;
+use crate::prelude::*;
+
+/// Live tasks of all executors
+static TASKS: Lazy<Mutex<BTreeMap<TaskId, Arc<TaskInfo>>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Woken, waiting in a run queue
+    Queued,
+    /// Being polled
+    Running,
+    /// Waiting to be woken
+    Waiting,
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> TaskState {
+        match value {
+            0 => TaskState::Queued,
+            1 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+/// Metadata and statistics of a task, shared with its wakers and handles
+pub struct TaskInfo {
+    id: TaskId,
+    name: Option<String>,
+    location: &'static Location<'static>,
+    pub(super) priority: TaskPriority,
+    cpu: AtomicU32,
+    state: AtomicU8,
+    polls: AtomicU64,
+    wakes: AtomicU64,
+    /// Poll times in timestamp increments, converted to time in snapshots
+    poll_cycles: AtomicU64,
+    max_poll_cycles: AtomicU64,
+}
+
+impl TaskInfo {
+    pub fn new(
+        id: TaskId, name: Option<String>, priority: Priority, location: &'static Location<'static>,
+    ) -> Self {
+        TaskInfo {
+            id,
+            name,
+            location,
+            priority: TaskPriority::new(priority),
+            cpu: AtomicU32::new(0),
+            state: AtomicU8::new(TaskState::Queued as u8),
+            polls: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            max_poll_cycles: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Where the task was spawned
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    pub fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Core whose executor runs the task
+    pub fn cpu(&self) -> u32 {
+        self.cpu.load(Ordering::Acquire)
+    }
+
+    pub(super) fn set_cpu(&self, cpu: u32) {
+        self.cpu.store(cpu, Ordering::Release);
+    }
+
+    pub(super) fn record_wake(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        self.state.store(TaskState::Queued as u8, Ordering::Relaxed);
+    }
+
+    pub(super) fn start_poll(&self) {
+        self.state.store(TaskState::Running as u8, Ordering::Relaxed);
+    }
+
+    /// Account a poll that took `cycles` increments of
+    /// [`timestamp`](crate::platform::time::timestamp)
+    pub(super) fn finish_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_poll_cycles.fetch_max(cycles, Ordering::Relaxed);
+
+        // Stays queued if it was woken while running
+        let _ = self.state.compare_exchange(
+            TaskState::Running as u8,
+            TaskState::Waiting as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn snapshot(&self) -> TaskSnapshot {
+        TaskSnapshot {
+            id: self.id,
+            name: self.name.clone(),
+            location: self.location,
+            priority: self.priority.get(),
+            cpu: self.cpu(),
+            state: self.state(),
+            polls: self.polls.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            poll_time: timestamp_to_duration(self.poll_cycles.load(Ordering::Relaxed)),
+            max_poll_time: timestamp_to_duration(self.max_poll_cycles.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// State of a task at some point
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub location: &'static Location<'static>,
+    pub priority: Priority,
+    /// Core whose executor runs the task
+    pub cpu: u32,
+    pub state: TaskState,
+    pub polls: u64,
+    pub wakes: u64,
+    /// Time spent in all polls
+    pub poll_time: Duration,
+    /// Time spent in the longest poll
+    pub max_poll_time: Duration,
+}
+
+pub(super) fn register(info: &Arc<TaskInfo>) {
+    TASKS.lock().insert(info.id, info.clone());
+}
+
+pub(super) fn unregister(id: TaskId) {
+    TASKS.lock().remove(&id);
+}
+
+/// Current state of every live task, ordered by ID
+pub fn snapshot() -> Vec<TaskSnapshot> {
+    TASKS.lock().values().map(|info| info.snapshot()).collect()
+}
+
+/// Current state of the live task `id`
+pub fn find(id: TaskId) -> Option<TaskSnapshot> {
+    TASKS.lock().get(&id).map(|info| info.snapshot())
+}
+
+/// Log the task table
+pub fn dump() {
+    let tasks = snapshot();
+
+    info!("{} tasks", tasks.len());
+    info!(
+        "{:>6} {:<16} {:>3} {:<8} {:<8} {:>8} {:>8} {:>14} {:>12}  location",
+        "id", "name", "cpu", "priority", "state", "polls", "wakes", "poll us", "max us"
+    );
+    for task in tasks {
+        info!(
+            "{:>6} {:<16} {:>3} {:<8} {:<8} {:>8} {:>8} {:>14} {:>12}  {}",
+            task.id.as_u64(),
+            task.name.as_deref().unwrap_or("-"),
+            task.cpu,
+            format!("{:?}", task.priority),
+            format!("{:?}", task.state),
+            task.polls,
+            task.wakes,
+            task.poll_time.num_microseconds().unwrap_or(i64::MAX),
+            task.max_poll_time.num_microseconds().unwrap_or(i64::MAX),
+            task.location
+        );
+    }
+}
+
+#[test_case]
+fn test_poll_time() {
+    use crate::platform::time::timestamp_frequency;
+
+    let info = TaskInfo::new(TaskId::new(), None, Priority::default(), Location::caller());
+    let second = timestamp_frequency();
+
+    for cycles in [second, second * 2].iter().copied() {
+        info.start_poll();
+        assert_eq!(info.state(), TaskState::Running);
+        info.finish_poll(cycles);
+    }
+
+    let snapshot = info.snapshot();
+    assert_eq!(snapshot.state, TaskState::Waiting);
+    assert_eq!(snapshot.polls, 2);
+    assert_eq!(snapshot.poll_time, Duration::seconds(3));
+    assert_eq!(snapshot.max_poll_time, Duration::seconds(2));
+}
@@ -0,0 +1,154 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use spin::Lazy;
+
+/// Legacy PIC IRQ lines
+pub const IRQ_COUNT: u8 = 16;
+
+struct IrqLine {
+    /// Interrupts received since boot
+    events: AtomicU64,
+    /// The handler masked the line, it's unmasked once the driver waits again
+    masked: AtomicBool,
+    waker: AtomicWaker,
+}
+
+static LINES: Lazy<Vec<IrqLine>> = Lazy::new(|| {
+    (0..IRQ_COUNT)
+        .map(|_| IrqLine {
+            events: AtomicU64::new(0),
+            masked: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        })
+        .collect()
+});
+
+fn line(irq: u8) -> &'static IrqLine {
+    assert!(irq < IRQ_COUNT, "IRQ {} doesn't exist", irq);
+    &LINES[usize::from(irq)]
+}
+
+/// Record an interrupt and wake the task waiting for it. Called by interrupt
+/// handlers after masking the line, drivers do the actual work on the
+/// executor.
+pub fn on_interrupt(irq: u8) {
+    let line = line(irq);
+    line.masked.store(true, Ordering::Release);
+    line.events.fetch_add(1, Ordering::Release);
+    line.waker.wake();
+}
+
+/// Interrupts received on `irq` since boot
+pub fn events(irq: u8) -> u64 {
+    line(irq).events.load(Ordering::Acquire)
+}
+
+/// Wait for interrupts on `irq`, which gets unmasked. Each IRQ line wakes a
+/// single task, the driver owning the device.
+///
+/// The returned future completes with the number of interrupts received since
+/// it was created, or since it last completed. A driver loop awaits it by
+/// reference, `(&mut irq).await`, so interrupts arriving while it works are
+/// counted too.
+pub fn irq(irq: u8) -> Irq {
+    let seen = events(irq);
+    crate::platform::interrupts::unmask_irq(irq);
+    Irq { irq, seen }
+}
+
+/// Future of [`irq`]
+pub struct Irq {
+    irq: u8,
+    seen: u64,
+}
+
+impl Irq {
+    pub fn number(&self) -> u8 {
+        self.irq
+    }
+}
+
+impl Future for Irq {
+    type Output = u64;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        let line = line(self.irq);
+        line.waker.register(cx.waker());
+
+        let events = line.events.load(Ordering::Acquire);
+        if events == self.seen {
+            // The driver is done with the device, let it interrupt again
+            if line.masked.swap(false, Ordering::AcqRel) {
+                crate::platform::interrupts::unmask_irq(self.irq);
+            }
+            return Poll::Pending;
+        }
+
+        let received = events.wrapping_sub(self.seen);
+        self.seen = events;
+        Poll::Ready(received)
+    }
+}
+
+#[test_case]
+fn test_irq_counts_events() {
+    use futures::task::noop_waker_ref;
+
+    // IRQ 2 is the PIC cascade, it never fires on its own
+    let mut cascade = Irq {
+        irq: 2,
+        seen: events(2),
+    };
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    assert!(Pin::new(&mut cascade).poll(&mut context).is_pending());
+    on_interrupt(2);
+    on_interrupt(2);
+    assert_eq!(Pin::new(&mut cascade).poll(&mut context), Poll::Ready(2));
+    assert!(Pin::new(&mut cascade).poll(&mut context).is_pending());
+}
+
+#[test_case]
+fn test_irq_unmasked_on_wait() {
+    use futures::task::noop_waker_ref;
+
+    let mut cascade = Irq {
+        irq: 2,
+        seen: events(2),
+    };
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    on_interrupt(2);
+    assert!(line(2).masked.load(Ordering::Acquire));
+    assert_eq!(Pin::new(&mut cascade).poll(&mut context), Poll::Ready(1));
+    assert!(line(2).masked.load(Ordering::Acquire));
+    assert!(Pin::new(&mut cascade).poll(&mut context).is_pending());
+    assert!(!line(2).masked.load(Ordering::Acquire));
+}
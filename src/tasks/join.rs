@@ -0,0 +1,261 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+use super::info::TaskInfo;
+use super::{Priority, TaskId};
+
+/// Reason a task didn't produce its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted or dropped before it completed
+    Cancelled,
+}
+
+/// Aborts a task. The executor drops its future and never polls it again.
+#[derive(Clone)]
+pub struct AbortHandle {
+    id: TaskId,
+    state: Arc<AbortState>,
+}
+
+struct AbortState {
+    aborted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl AbortHandle {
+    pub fn new(id: TaskId) -> Self {
+        let state = Arc::new(AbortState {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        Self { id, state }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Request the task to be aborted. The task is woken, so it takes effect
+    /// the next time its executor runs, on whichever core that is. Aborting a
+    /// finished task does nothing.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.waker.wake();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.aborted.load(Ordering::Acquire)
+    }
+
+    /// Waker of the task, used to schedule it when it is aborted
+    pub(super) fn register(&self, waker: &Waker) {
+        self.state.waker.register(waker);
+    }
+}
+
+enum JoinStatus<T> {
+    Running,
+    Finished(T),
+    Cancelled,
+    Joined,
+}
+
+/// Shared between a spawned task and its [`JoinHandle`]
+struct JoinState<T> {
+    status: Mutex<JoinStatus<T>>,
+    waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, status: JoinStatus<T>) {
+        *self.status.lock() = status;
+        self.waker.wake();
+    }
+}
+
+/// Completion side of a [`JoinHandle`]. If it is dropped before the task
+/// completes, e.g. because its future was dropped, the task is reported as
+/// cancelled.
+pub struct JoinSender<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinSender<T> {
+    /// Wrap `future` so its output is sent to the handle
+    pub fn wrap<F>(self, future: F) -> impl Future<Output = ()>
+    where
+        F: Future<Output = T>, {
+        async move {
+            let output = future.await;
+            self.state.finish(JoinStatus::Finished(output));
+        }
+    }
+}
+
+impl<T> Drop for JoinSender<T> {
+    fn drop(&mut self) {
+        let running = matches!(*self.state.status.lock(), JoinStatus::Running);
+        if running {
+            self.state.finish(JoinStatus::Cancelled);
+        }
+    }
+}
+
+/// Awaits the output of a spawned task. Dropping the handle detaches the task,
+/// which keeps running.
+pub struct JoinHandle<T> {
+    abort: AbortHandle,
+    info: Arc<TaskInfo>,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.abort.id()
+    }
+
+    /// Abort the task, awaiting the handle then returns
+    /// [`JoinError::Cancelled`] unless the task already completed
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Name, spawn location and statistics of the task
+    pub fn info(&self) -> &TaskInfo {
+        &self.info
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.info.priority.get()
+    }
+
+    /// Change the scheduling class of the task, from its next wake-up on
+    pub fn set_priority(&self, priority: Priority) {
+        self.info.priority.set(priority);
+    }
+
+    /// Whether the task completed or was cancelled
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.status.lock(), JoinStatus::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.state.waker.register(cx.waker());
+
+        let mut status = self.state.status.lock();
+        match mem::replace(&mut *status, JoinStatus::Joined) {
+            JoinStatus::Running => {
+                *status = JoinStatus::Running;
+                Poll::Pending
+            },
+            JoinStatus::Finished(output) => Poll::Ready(Ok(output)),
+            JoinStatus::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            JoinStatus::Joined => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+/// Create the completion and awaiting sides of a task
+pub fn channel<T>(abort: AbortHandle, info: Arc<TaskInfo>) -> (JoinSender<T>, JoinHandle<T>) {
+    let state = Arc::new(JoinState {
+        status: Mutex::new(JoinStatus::Running),
+        waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle {
+        abort,
+        info,
+        state: state.clone(),
+    };
+    (JoinSender { state }, handle)
+}
+
+/// Wrap `future` so its output can be awaited through the returned handle
+pub fn joinable<F>(
+    abort: AbortHandle, info: Arc<TaskInfo>, future: F,
+) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future, {
+    let (sender, handle) = channel(abort, info);
+    (sender.wrap(future), handle)
+}
+
+#[test_case]
+fn test_join_handle_output() {
+    use alloc::boxed::Box;
+
+    use futures::task::noop_waker_ref;
+
+    let id = TaskId::new();
+    let info = Arc::new(TaskInfo::new(
+        id,
+        None,
+        Priority::Normal,
+        core::panic::Location::caller(),
+    ));
+    let (future, mut handle) = joinable(AbortHandle::new(id), info, async { 42 });
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    assert!(!handle.is_finished());
+    assert!(Box::pin(future).as_mut().poll(&mut context).is_ready());
+    assert_eq!(Pin::new(&mut handle).poll(&mut context), Poll::Ready(Ok(42)));
+}
+
+#[test_case]
+fn test_join_handle_cancelled() {
+    use futures::task::noop_waker_ref;
+
+    let id = TaskId::new();
+    let info = Arc::new(TaskInfo::new(
+        id,
+        None,
+        Priority::Normal,
+        core::panic::Location::caller(),
+    ));
+    let (future, mut handle) = joinable(AbortHandle::new(id), info, async { 42 });
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    drop(future);
+    assert!(handle.is_finished());
+    assert_eq!(
+        Pin::new(&mut handle).poll(&mut context),
+        Poll::Ready(Err(JoinError::Cancelled))
+    );
+}
@@ -20,11 +20,22 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 
 use chrono::Duration;
+use futures::future::{poll_fn, select, Either};
+use futures::{pin_mut, Stream};
+
+use super::timer::{Clock, TimerId};
+use crate::platform::time::{duration_to_ticks, ticks_to_duration};
+
+/// The deadline of a [`timeout`] passed before its future completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
 
 #[inline]
 pub async fn yield_now() {
@@ -36,6 +47,76 @@ pub async fn sleep(duration: Duration) {
     Sleep::new(duration).await
 }
 
+/// Time on the clock of the executing task: the uptime, or the virtual time of
+/// a deterministic executor
+pub fn now() -> Duration {
+    ticks_to_duration(Clock::current().now())
+}
+
+/// Run `future` for at most `duration`, counted from this call. The future is
+/// dropped if it doesn't complete in time.
+pub fn timeout<F>(duration: Duration, future: F) -> impl Future<Output = Result<F::Output, Elapsed>>
+where
+    F: Future, {
+    let sleep = Sleep::new(duration);
+
+    async move {
+        pin_mut!(future);
+        match select(future, sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed),
+        }
+    }
+}
+
+/// Yield every `period`, starting one period from now
+pub fn interval(period: Duration) -> Interval {
+    let period = duration_to_ticks(period).max(1);
+    let clock = Clock::current();
+    Interval {
+        period,
+        deadline: clock.now() + period,
+        clock,
+        sleep: None,
+    }
+}
+
+/// Run both futures until one completes, dropping the other
+pub async fn race<A, B>(a: A, b: B) -> Either<A::Output, B::Output>
+where
+    A: Future,
+    B: Future, {
+    pin_mut!(a);
+    pin_mut!(b);
+    match select(a, b).await {
+        Either::Left((output, _)) => Either::Left(output),
+        Either::Right((output, _)) => Either::Right(output),
+    }
+}
+
+/// Run all `futures` until one completes, dropping the others. Returns the
+/// output of the winner and its index. Futures that are ready at the same time
+/// are won by the first one.
+pub async fn race_all<I>(futures: I) -> (<I::Item as Future>::Output, usize)
+where
+    I: IntoIterator,
+    I::Item: Future, {
+    let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    assert!(!futures.is_empty(), "race between no futures");
+
+    poll_fn(|cx| {
+        futures
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, future)| match future.as_mut().poll(cx) {
+                Poll::Ready(output) => Some((output, index)),
+                Poll::Pending => None,
+            })
+            .map_or(Poll::Pending, Poll::Ready)
+    })
+    .await
+}
+
 struct YieldNow {
     inner: bool,
 }
@@ -49,9 +130,10 @@ impl YieldNow {
 impl Future for YieldNow {
     type Output = ();
 
-    // The futures executor is implemented as a FIFO queue, so all this future
-    // does is re-schedule the future back to the end of the queue, giving room
-    // for other futures to progress.
+    // Waking the task queues it at the back of the ready queue of its
+    // priority. Every other ready task of that priority runs before it again,
+    // and the weighted rounds of the executor still give the lower priorities
+    // their share, so the task can't starve them by yielding.
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         if !self.inner {
             self.inner = true;
@@ -63,20 +145,148 @@ impl Future for YieldNow {
     }
 }
 
+/// Resolves once the clock of the task that created it counts past its
+/// deadline
 struct Sleep {
-    duration: Duration,
+    clock: Clock,
+    deadline: u64,
+    timer: Option<TimerId>,
 }
 
 impl Sleep {
     fn new(duration: Duration) -> Self {
-        Self { duration }
+        let clock = Clock::current();
+        let deadline = clock.now() + duration_to_ticks(duration);
+        Self::until(clock, deadline)
+    }
+
+    fn until(clock: Clock, deadline: u64) -> Self {
+        Self {
+            clock,
+            deadline,
+            timer: None,
+        }
     }
 }
 
 impl Future for Sleep {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
-        todo!()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.clock.is_expired(self.deadline) {
+            if let Some(id) = self.timer.take() {
+                self.clock.cancel(self.deadline, id);
+            }
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline;
+        match self.timer {
+            Some(id) if self.clock.update(deadline, id, cx.waker()) => {},
+            // Fired between the expiration check and the update, poll again
+            Some(_) => cx.waker().wake_by_ref(),
+            None => self.timer = Some(self.clock.register(deadline, cx.waker().clone())),
+        }
+        Poll::Pending
     }
 }
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            self.clock.cancel(self.deadline, id);
+        }
+    }
+}
+
+/// Stream of [`interval`] ticks. Deadlines stay multiples of the period from
+/// the start, so late wake-ups don't accumulate drift.
+pub struct Interval {
+    /// In timer ticks
+    period: u64,
+    deadline: u64,
+    clock: Clock,
+    sleep: Option<Sleep>,
+}
+
+impl Interval {
+    /// Wait for the next tick. Returns the number of periods since the
+    /// previous one, more than one if ticks were missed; missed ticks are
+    /// skipped rather than fired in a burst.
+    pub async fn tick(&mut self) -> u64 {
+        poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+        let deadline = self.deadline;
+        let clock = &self.clock;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Sleep::until(clock.clone(), deadline));
+        if Pin::new(sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.sleep = None;
+
+        let periods = self.clock.now().saturating_sub(deadline) / self.period + 1;
+        self.deadline = deadline + periods * self.period;
+        Poll::Ready(periods)
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u64>> {
+        self.poll_tick(cx).map(Some)
+    }
+}
+
+#[test_case]
+fn test_race_drops_loser() {
+    use futures::future::{pending, ready};
+    use futures::task::noop_waker_ref;
+
+    let mut context = Context::from_waker(noop_waker_ref());
+    let race = race(pending::<()>(), ready(7));
+    pin_mut!(race);
+    assert_eq!(race.poll(&mut context), Poll::Ready(Either::Right(7)));
+
+    let race = race_all(alloc::vec![ready(1), ready(2)]);
+    pin_mut!(race);
+    assert_eq!(race.poll(&mut context), Poll::Ready((1, 0)));
+}
+
+#[test_case]
+fn test_timeout() {
+    use futures::future::{pending, ready};
+
+    use super::executor::TaskExecutor;
+
+    let results = TaskExecutor::deterministic(0).block_on(async {
+        (
+            timeout(Duration::seconds(1), ready(7)).await,
+            timeout(Duration::seconds(1), sleep(Duration::milliseconds(10))).await,
+            timeout(Duration::milliseconds(10), pending::<()>()).await,
+            timeout(Duration::milliseconds(10), sleep(Duration::seconds(1))).await,
+        )
+    });
+    assert_eq!(results, (Ok(7), Ok(()), Err(Elapsed), Err(Elapsed)));
+}
+
+#[test_case]
+fn test_interval_skips_missed_ticks() {
+    use super::executor::TaskExecutor;
+
+    let periods = TaskExecutor::deterministic(0).block_on(async {
+        let mut interval = interval(Duration::milliseconds(10));
+        let first = interval.tick().await;
+        let second = interval.tick().await;
+        // Sleeps through three deadlines, they are reported by a single tick
+        sleep(Duration::milliseconds(35)).await;
+        let late = interval.tick().await;
+        let next = interval.tick().await;
+        (first, second, late, next)
+    });
+    assert_eq!(periods, (1, 1, 3, 1));
+}
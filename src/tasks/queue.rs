@@ -0,0 +1,92 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::sync::atomic::{self, AtomicBool, Ordering};
+
+use crossbeam_queue::SegQueue;
+
+use super::{Priority, TaskId};
+use crate::platform::halt::temporal_halt_if;
+use crate::platform::smp;
+
+/// Run queue of the executor of one core, with one FIFO queue of ready tasks
+/// per [`Priority`]
+pub struct ReadyQueues {
+    cpu: u32,
+    halted: AtomicBool,
+    queues: [SegQueue<TaskId>; 4],
+}
+
+impl ReadyQueues {
+    pub fn new(cpu: u32) -> Self {
+        ReadyQueues {
+            cpu,
+            halted: AtomicBool::new(false),
+            queues: [SegQueue::new(), SegQueue::new(), SegQueue::new(), SegQueue::new()],
+        }
+    }
+
+    /// Core that runs the tasks of this queue
+    pub fn cpu(&self) -> u32 {
+        self.cpu
+    }
+
+    /// Queue a task, waking its core if it is halted
+    pub fn push(&self, task_id: TaskId, priority: Priority) {
+        self.queues[priority as usize].push(task_id);
+        self.notify();
+    }
+
+    pub fn pop(&self, priority: Priority) -> Option<TaskId> {
+        self.queues[priority as usize].pop()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(SegQueue::is_empty)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    /// Wake the core of this queue if it is halted
+    pub fn notify(&self) {
+        // Pairs with the store in `halt_if_idle`: either the core sees the new
+        // work before halting, or we see it halted and interrupt it.
+        atomic::fence(Ordering::SeqCst);
+        if self.is_halted() {
+            smp::wake_cpu(self.cpu);
+        }
+    }
+
+    /// Halt the executing core until the next interrupt, unless this queue or
+    /// `is_idle` reports pending work. Must be called from the queue's core.
+    pub fn halt_if_idle<F>(&self, is_idle: F)
+    where
+        F: FnOnce() -> bool, {
+        temporal_halt_if(|| {
+            self.halted.store(true, Ordering::SeqCst);
+            self.is_empty() && is_idle()
+        });
+        self.halted.store(false, Ordering::SeqCst);
+    }
+}
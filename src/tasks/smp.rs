@@ -0,0 +1,145 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{self, Ordering};
+
+use crossbeam_queue::SegQueue;
+use spin::RwLock;
+
+use super::queue::ReadyQueues;
+use super::spawner::{self, Spawner};
+use super::{MigratingTask, Priority, Task, TaskId};
+use crate::platform::interrupts::without_interrupts;
+
+struct Core {
+    ready: Arc<ReadyQueues>,
+    spawner: Spawner,
+    /// Ready tasks the core gave up for idle cores to take
+    offered: SegQueue<MigratingTask>,
+}
+
+/// Executors of all cores. Each core runs its own [`TaskExecutor`] with a
+/// local run queue. Idle cores take spawn requests from the global spawner
+/// and from busy cores. Started tasks whose future is `Send` move too: a busy
+/// core that sees a halted one offers it ready tasks, which the idle core
+/// takes. Tasks built on their executor, e.g. SIPs, stay on their core.
+///
+/// [`TaskExecutor`]: super::executor::TaskExecutor
+static CORES: RwLock<Vec<Core>> = RwLock::new(Vec::new());
+
+/// Make a running executor reachable from other cores
+pub fn register(ready: Arc<ReadyQueues>, spawner: Spawner) {
+    let core = Core {
+        ready,
+        spawner,
+        offered: SegQueue::new(),
+    };
+    // Interrupt handlers read the table when they spawn tasks
+    without_interrupts(|| CORES.write().push(core));
+}
+
+/// Take work for the core `cpu`: a task offered by a busy core, or a spawn
+/// request from the global spawner or another core
+pub fn steal(cpu: u32) -> Option<Task> {
+    let cores = CORES.read();
+    if let Some(task) = cores.iter().find_map(|core| core.offered.pop()) {
+        return Some(task.into_task());
+    }
+
+    let request = spawner::spawner().take().or_else(|| {
+        cores
+            .iter()
+            .filter(|core| core.ready.cpu() != cpu)
+            .find_map(|core| core.spawner.take())
+    })?;
+    drop(cores);
+    Some(request())
+}
+
+/// Whether [`steal`] would find nothing
+pub fn nothing_to_steal() -> bool {
+    let cores = CORES.read();
+    spawner::spawner().is_empty()
+        && cores
+            .iter()
+            .all(|core| core.spawner.is_empty() && core.offered.is_empty())
+}
+
+/// Whether a core other than `cpu` is halted for lack of work
+pub fn has_idle_core(cpu: u32) -> bool {
+    CORES
+        .read()
+        .iter()
+        .any(|core| core.ready.cpu() != cpu && core.ready.is_halted())
+}
+
+/// Offer a ready task of the core `cpu` to idle cores. Tasks that may not
+/// move are handed back.
+pub fn offer(cpu: u32, task: Task) -> Result<(), Task> {
+    let task = MigratingTask::new(task)?;
+    let cores = CORES.read();
+    match cores.iter().find(|core| core.ready.cpu() == cpu) {
+        Some(core) => core.offered.push(task),
+        None => return Err(task.into_task()),
+    }
+    drop(cores);
+
+    notify_idle();
+    Ok(())
+}
+
+/// Queue a task on the run queue of the core `cpu`, returns `false` if that
+/// core has no registered executor
+pub fn push(cpu: u32, task_id: TaskId, priority: Priority) -> bool {
+    match CORES.read().iter().find(|core| core.ready.cpu() == cpu) {
+        Some(core) => {
+            core.ready.push(task_id, priority);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Wake a halted core, if any, so it steals the work that was just spawned or
+/// offered
+pub fn notify_idle() {
+    // Pairs with `ReadyQueues::halt_if_idle`, see `ReadyQueues::notify`
+    atomic::fence(Ordering::SeqCst);
+    let cores = CORES.read();
+    if let Some(core) = cores.iter().find(|core| core.ready.is_halted()) {
+        core.ready.notify();
+    }
+}
+
+#[test_case]
+fn test_only_send_tasks_migrate() {
+    let spawner = Spawner::new(Arc::new(SegQueue::new()));
+    spawner.spawn(async {});
+    spawner.spawn_with(|| async {});
+
+    let send = spawner.take().expect("no spawn request")();
+    let local = spawner.take().expect("no spawn request")();
+    assert!(MigratingTask::new(send).is_ok());
+    assert!(MigratingTask::new(local).is_err());
+}
@@ -0,0 +1,100 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+
+use crossbeam_queue::SegQueue;
+use spin::Lazy;
+
+use super::builder::Builder;
+use super::join::JoinHandle;
+use super::{smp, Priority, Task};
+
+/// Builds a task on the executor, so its future doesn't need to be `Send`
+pub type SpawnRequest = Box<dyn FnOnce() -> Task + Send>;
+
+static GLOBAL_SPAWNER: Lazy<Spawner> = Lazy::new(|| Spawner::new(Arc::new(SegQueue::new())));
+
+/// Handle to spawn tasks from any task or interrupt handler. New tasks are
+/// picked up by the executor owning the queue, or by an idle core stealing
+/// them.
+#[derive(Clone)]
+pub struct Spawner {
+    requests: Arc<SegQueue<SpawnRequest>>,
+}
+
+impl Spawner {
+    pub fn new(requests: Arc<SegQueue<SpawnRequest>>) -> Self {
+        Spawner { requests }
+    }
+
+    /// Spawn `future`, the task may move to an idle core while it runs
+    #[track_caller]
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static, {
+        Builder::new().spawn_send(self, future)
+    }
+
+    /// Spawn the future built by `factory`. The factory runs on the executor,
+    /// so the future itself doesn't need to be `Send`, e.g. a SIP. The task
+    /// stays on the core that built it.
+    #[track_caller]
+    pub fn spawn_with<F, Fut>(&self, factory: F) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static, {
+        Builder::new().spawn(self, factory)
+    }
+
+    /// Like [`Spawner::spawn_with`], scheduling the task as `priority`
+    #[track_caller]
+    pub fn spawn_with_priority<F, Fut>(&self, priority: Priority, factory: F) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static, {
+        Builder::new().priority(priority).spawn(self, factory)
+    }
+
+    pub(super) fn push(&self, request: SpawnRequest) {
+        self.requests.push(request);
+        smp::notify_idle();
+    }
+
+    pub(super) fn take(&self) -> Option<SpawnRequest> {
+        self.requests.pop()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/// Spawner shared by the executors of all cores
+pub fn spawner() -> &'static Spawner {
+    &GLOBAL_SPAWNER
+}
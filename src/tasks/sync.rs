@@ -0,0 +1,68 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Async synchronization primitives. Waiting tasks yield to the executor
+// instead of spinning, so they can't deadlock against a task holding the lock
+// on the same core.
+
+#[cfg(test)]
+use alloc::sync::Arc;
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use core::task::Waker;
+
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::notify::{Notified, Notify};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Acquire, AcquireError, Semaphore, SemaphorePermit};
+
+/// Counts the wake-ups of a waker, to check which waiters are woken
+#[cfg(test)]
+struct WakeCounter(AtomicUsize);
+
+#[cfg(test)]
+impl WakeCounter {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl alloc::task::Wake for WakeCounter {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+fn wake_counter() -> (Arc<WakeCounter>, Waker) {
+    let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+    (counter.clone(), Waker::from(counter))
+}
+
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+pub mod rwlock;
+pub mod semaphore;
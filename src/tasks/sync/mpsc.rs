@@ -0,0 +1,180 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Poll;
+
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+use super::Semaphore;
+
+/// The receiver is gone, the value is given back
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity
+    Full(T),
+    /// The receiver is gone
+    Closed(T),
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// One permit per free slot
+    capacity: Semaphore,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    receiver: AtomicWaker,
+}
+
+impl<T> Shared<T> {
+    fn push(&self, value: T) {
+        self.queue.lock().push_back(value);
+        self.receiver.wake();
+    }
+}
+
+/// Sends values to a [`Receiver`], waiting while the channel is full
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.shared.capacity.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.shared.push(value);
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.shared.capacity.try_acquire() {
+            Some(permit) => permit.forget(),
+            None if self.is_closed() => return Err(TrySendError::Closed(value)),
+            None => return Err(TrySendError::Full(value)),
+        }
+        self.shared.push(value);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.receiver.wake();
+        }
+    }
+}
+
+/// Receives the values of all [`Sender`]s in the order they were sent
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next value, `None` once all senders are gone and the
+    /// channel is drained
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| {
+            self.shared.receiver.register(cx.waker());
+            match self.try_recv() {
+                Some(value) => Poll::Ready(Some(value)),
+                // Look again, a value may have been sent right before the
+                // last sender was dropped
+                None if self.shared.senders.load(Ordering::Acquire) == 0 => Poll::Ready(self.try_recv()),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.shared.queue.lock().pop_front()?;
+        self.shared.capacity.add_permits(1);
+        Some(value)
+    }
+
+    /// Stop accepting values, the ones already sent can still be received
+    pub fn close(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.capacity.close();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Create a channel holding up to `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be positive");
+
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: Semaphore::new(capacity),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        receiver: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+#[test_case]
+fn test_channel_capacity() {
+    let (sender, mut receiver) = channel(1);
+
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Some(1));
+    assert_eq!(sender.try_send(3), Ok(()));
+
+    drop(receiver);
+    assert_eq!(sender.try_send(4), Err(TrySendError::Closed(4)));
+}
@@ -0,0 +1,151 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::Semaphore;
+
+/// Mutual exclusion lock whose waiters yield to the executor. The lock is
+/// fair, it is handed over in request order.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        match self.semaphore.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => unreachable!("mutex semaphore is never closed"),
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// No locking needed, the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// Exclusive access to the value of a [`Mutex`], unlocked when dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+#[test_case]
+fn test_mutex_handed_over_in_order() {
+    use alloc::boxed::Box;
+    use core::future::Future;
+    use core::task::{Context, Poll};
+
+    let mutex = Mutex::new(0);
+    let (first_woken, first_waker) = super::wake_counter();
+    let (second_woken, second_waker) = super::wake_counter();
+    let mut first_context = Context::from_waker(&first_waker);
+    let mut second_context = Context::from_waker(&second_waker);
+
+    let guard = mutex.try_lock().unwrap();
+    let mut first = Box::pin(mutex.lock());
+    let mut second = Box::pin(mutex.lock());
+    assert!(first.as_mut().poll(&mut first_context).is_pending());
+    assert!(second.as_mut().poll(&mut second_context).is_pending());
+    assert!(mutex.try_lock().is_none());
+
+    // Only the oldest waiter is woken, and the lock stays reserved for it
+    drop(guard);
+    assert_eq!((first_woken.count(), second_woken.count()), (1, 0));
+    assert!(second.as_mut().poll(&mut second_context).is_pending());
+    match first.as_mut().poll(&mut first_context) {
+        Poll::Ready(mut guard) => *guard += 1,
+        Poll::Pending => panic!("lock not handed over"),
+    }
+
+    assert_eq!(second_woken.count(), 1);
+    match second.as_mut().poll(&mut second_context) {
+        Poll::Ready(guard) => assert_eq!(*guard, 1),
+        Poll::Pending => panic!("lock not handed over"),
+    }
+}
+
+#[test_case]
+fn test_mutex_dropped_waiter() {
+    use alloc::boxed::Box;
+    use core::future::Future;
+
+    use futures::task::noop_waker_ref;
+
+    let mutex = Mutex::new(());
+    let mut context = core::task::Context::from_waker(noop_waker_ref());
+
+    let guard = mutex.try_lock().unwrap();
+    let mut waiter = Box::pin(mutex.lock());
+    assert!(waiter.as_mut().poll(&mut context).is_pending());
+
+    // The lock is passed to a waiter that gives up before it is polled again
+    drop(guard);
+    drop(waiter);
+    assert!(mutex.try_lock().is_some());
+}
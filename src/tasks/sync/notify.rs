@@ -0,0 +1,215 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+const WAITING: u8 = 0;
+const NOTIFIED_ONE: u8 = 1;
+const NOTIFIED_ALL: u8 = 2;
+
+struct Waiter {
+    waker: Waker,
+    state: Arc<AtomicU8>,
+}
+
+struct State {
+    /// Stored by `notify_one` when nobody waits
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+}
+
+/// Wakes waiting tasks without passing any data
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wait for a notification
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Wake the oldest waiter. If nobody waits, the next call to
+    /// [`Notify::notified`] completes immediately.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.state.store(NOTIFIED_ONE, Ordering::Release);
+                waiter.waker.wake();
+            },
+            None => state.permit = true,
+        }
+    }
+
+    /// Wake every task waiting right now
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for waiter in state.waiters.drain(..) {
+            waiter.state.store(NOTIFIED_ALL, Ordering::Release);
+            waiter.waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future of [`Notify::notified`]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<AtomicU8>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+
+        match &self.waiter {
+            None if state.permit => {
+                state.permit = false;
+                Poll::Ready(())
+            },
+            None => {
+                let waiter = Arc::new(AtomicU8::new(WAITING));
+                state.waiters.push_back(Waiter {
+                    waker: cx.waker().clone(),
+                    state: waiter.clone(),
+                });
+                self.waiter = Some(waiter);
+                Poll::Pending
+            },
+            Some(waiter) if waiter.load(Ordering::Acquire) == WAITING => {
+                if let Some(entry) = state
+                    .waiters
+                    .iter_mut()
+                    .find(|entry| Arc::ptr_eq(&entry.state, waiter))
+                {
+                    entry.waker = cx.waker().clone();
+                }
+                Poll::Pending
+            },
+            Some(_) => {
+                drop(state);
+                self.waiter = None;
+                Poll::Ready(())
+            },
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+
+        let mut state = self.notify.state.lock();
+        match waiter.load(Ordering::Acquire) {
+            WAITING => state.waiters.retain(|entry| !Arc::ptr_eq(&entry.state, &waiter)),
+            NOTIFIED_ONE => {
+                // Don't lose a notification meant for a single waiter
+                drop(state);
+                self.notify.notify_one();
+            },
+            _ => {},
+        }
+    }
+}
+
+#[test_case]
+fn test_notify_one_in_order() {
+    use alloc::boxed::Box;
+
+    let notify = Notify::new();
+    let (first_woken, first_waker) = super::wake_counter();
+    let (second_woken, second_waker) = super::wake_counter();
+    let mut first_context = Context::from_waker(&first_waker);
+    let mut second_context = Context::from_waker(&second_waker);
+
+    let mut first = Box::pin(notify.notified());
+    let mut second = Box::pin(notify.notified());
+    assert!(first.as_mut().poll(&mut first_context).is_pending());
+    assert!(second.as_mut().poll(&mut second_context).is_pending());
+
+    notify.notify_one();
+    assert_eq!((first_woken.count(), second_woken.count()), (1, 0));
+    assert!(second.as_mut().poll(&mut second_context).is_pending());
+
+    // A notified waiter that is dropped passes its notification on
+    drop(first);
+    assert_eq!(second_woken.count(), 1);
+    assert!(second.as_mut().poll(&mut second_context).is_ready());
+}
+
+#[test_case]
+fn test_notify_waiters() {
+    use alloc::boxed::Box;
+
+    use futures::task::noop_waker_ref;
+
+    let notify = Notify::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let mut first = Box::pin(notify.notified());
+    let mut second = Box::pin(notify.notified());
+    assert!(first.as_mut().poll(&mut context).is_pending());
+    assert!(second.as_mut().poll(&mut context).is_pending());
+
+    // Wakes everyone waiting, but stores nothing for later
+    notify.notify_waiters();
+    assert!(first.as_mut().poll(&mut context).is_ready());
+    assert!(second.as_mut().poll(&mut context).is_ready());
+    let mut late = Box::pin(notify.notified());
+    assert!(late.as_mut().poll(&mut context).is_pending());
+
+    // Without waiters, `notify_one` is stored for the next one
+    drop(late);
+    notify.notify_one();
+    assert!(Box::pin(notify.notified()).as_mut().poll(&mut context).is_ready());
+}
@@ -0,0 +1,161 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+/// The sender was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    receiver: AtomicWaker,
+}
+
+/// Sends a single value to a [`Receiver`]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, giving it back if the receiver is gone
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock();
+        if !state.receiver_alive {
+            return Err(value);
+        }
+        state.value = Some(value);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().sender_alive = false;
+        self.shared.receiver.wake();
+    }
+}
+
+/// Awaits the value of a [`Sender`]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Take the value if it was sent already
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.state.lock().value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.shared.receiver.register(cx.waker());
+
+        let mut state = self.shared.state.lock();
+        match state.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if !state.sender_alive => Poll::Ready(Err(RecvError)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().receiver_alive = false;
+    }
+}
+
+/// Create a channel carrying a single value
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            value: None,
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        receiver: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+#[test_case]
+fn test_oneshot_send() {
+    use alloc::boxed::Box;
+
+    let (sender, receiver) = channel();
+    let (woken, waker) = super::wake_counter();
+    let mut context = Context::from_waker(&waker);
+    let mut receiver = Box::pin(receiver);
+
+    assert!(receiver.as_mut().poll(&mut context).is_pending());
+    assert_eq!(sender.send(42), Ok(()));
+    assert_eq!(woken.count(), 1);
+    assert_eq!(receiver.as_mut().poll(&mut context), Poll::Ready(Ok(42)));
+}
+
+#[test_case]
+fn test_oneshot_dropped() {
+    use alloc::boxed::Box;
+
+    let (sender, receiver) = channel::<u32>();
+    let (woken, waker) = super::wake_counter();
+    let mut context = Context::from_waker(&waker);
+    let mut receiver = Box::pin(receiver);
+
+    // Dropping the sender wakes the receiver with an error
+    assert!(receiver.as_mut().poll(&mut context).is_pending());
+    drop(sender);
+    assert_eq!(woken.count(), 1);
+    assert_eq!(receiver.as_mut().poll(&mut context), Poll::Ready(Err(RecvError)));
+
+    // Sending to a dropped receiver gives the value back
+    let (sender, receiver) = channel();
+    assert!(!sender.is_closed());
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(7), Err(7));
+}
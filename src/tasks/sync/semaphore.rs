@@ -0,0 +1,322 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::{BTreeSet, VecDeque};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+/// The semaphore was closed while waiting for permits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    next_id: u64,
+    /// Tasks waiting for permits, served in order. A waiter is removed once
+    /// its permits are handed over.
+    waiters: VecDeque<Waiter>,
+    /// Waiters whose permits were handed over, but which weren't polled yet
+    granted: BTreeSet<u64>,
+}
+
+impl State {
+    fn position(&self, id: u64) -> Option<usize> {
+        self.waiters.iter().position(|waiter| waiter.id == id)
+    }
+
+    /// Hand permits over to waiters, in order
+    fn grant(&mut self) {
+        while let Some(waiter) = self.waiters.front() {
+            if waiter.permits > self.permits {
+                break;
+            }
+            self.permits -= waiter.permits;
+            if let Some(waiter) = self.waiters.pop_front() {
+                self.granted.insert(waiter.id);
+                waiter.waker.wake();
+            }
+        }
+    }
+}
+
+/// Fair counting semaphore, permits are handed out in request order
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                closed: false,
+                next_id: 0,
+                waiters: VecDeque::new(),
+                granted: BTreeSet::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        state.grant();
+    }
+
+    /// Wait for a permit
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Wait for `permits` permits, they are granted all at once
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+            done: false,
+        }
+    }
+
+    /// Take `permits` permits if they are available and nobody is waiting
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.closed || !state.waiters.is_empty() || state.permits < permits {
+            return None;
+        }
+        state.permits -= permits;
+        Some(SemaphorePermit::new(self, permits))
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Fail all pending and future acquisitions. Permits already granted stay
+    /// valid, even if their waiter wasn't polled since.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in state.waiters.drain(..) {
+            waiter.waker.wake();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+/// Future of [`Semaphore::acquire_many`]
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Set once queued
+    id: Option<u64>,
+    done: bool,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let mut state = semaphore.state.lock();
+
+        let granted = match self.id {
+            None if state.closed => return Poll::Ready(Err(AcquireError)),
+            None if state.waiters.is_empty() && state.permits >= permits => {
+                state.permits -= permits;
+                true
+            },
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    permits,
+                    waker: cx.waker().clone(),
+                });
+                self.id = Some(id);
+                false
+            },
+            Some(id) => match state.position(id) {
+                Some(index) => {
+                    state.waiters[index].waker = cx.waker().clone();
+                    false
+                },
+                // Dequeued by `grant`, which took the permits for us
+                None if state.granted.remove(&id) => true,
+                // Dequeued because the semaphore was closed
+                None => {
+                    self.done = true;
+                    return Poll::Ready(Err(AcquireError));
+                },
+            },
+        };
+
+        if granted {
+            self.done = true;
+            Poll::Ready(Ok(SemaphorePermit::new(semaphore, permits)))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) if !self.done => id,
+            _ => return,
+        };
+
+        let mut state = self.semaphore.state.lock();
+        match state.position(id) {
+            Some(index) => {
+                state.waiters.remove(index);
+            },
+            // Permits were granted but never handed over
+            None if state.granted.remove(&id) => state.permits += self.permits,
+            None => {},
+        }
+        // Waiters behind us may be satisfiable now
+        state.grant();
+    }
+}
+
+/// Permits taken from a [`Semaphore`], released when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    fn new(semaphore: &'a Semaphore, permits: usize) -> Self {
+        SemaphorePermit { semaphore, permits }
+    }
+
+    /// Keep the permits taken, e.g. to release them later with
+    /// [`Semaphore::add_permits`]
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+#[test_case]
+fn test_semaphore_fifo() {
+    use alloc::boxed::Box;
+
+    use futures::task::noop_waker_ref;
+
+    let semaphore = Semaphore::new(1);
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let permit = semaphore.try_acquire().unwrap();
+    let mut first = Box::pin(semaphore.acquire());
+    let mut second = Box::pin(semaphore.acquire());
+    assert!(first.as_mut().poll(&mut context).is_pending());
+    assert!(second.as_mut().poll(&mut context).is_pending());
+
+    // A cancelled waiter gives its turn to the next one
+    drop(permit);
+    drop(first);
+    let permit = match second.as_mut().poll(&mut context) {
+        Poll::Ready(Ok(permit)) => permit,
+        _ => panic!("permit not granted"),
+    };
+    assert_eq!(semaphore.available_permits(), 0);
+    drop(permit);
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[test_case]
+fn test_semaphore_close_after_grant() {
+    use alloc::boxed::Box;
+
+    use futures::task::noop_waker_ref;
+
+    let semaphore = Semaphore::new(1);
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let permit = semaphore.try_acquire().unwrap();
+    let mut granted = Box::pin(semaphore.acquire());
+    let mut refused = Box::pin(semaphore.acquire());
+    assert!(granted.as_mut().poll(&mut context).is_pending());
+    assert!(refused.as_mut().poll(&mut context).is_pending());
+
+    // Permits granted before closing are still handed over
+    drop(permit);
+    semaphore.close();
+    assert!(matches!(
+        refused.as_mut().poll(&mut context),
+        Poll::Ready(Err(AcquireError))
+    ));
+    let permit = match granted.as_mut().poll(&mut context) {
+        Poll::Ready(Ok(permit)) => permit,
+        _ => panic!("granted permit lost"),
+    };
+    drop(permit);
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[test_case]
+fn test_semaphore_drop_granted_waiter() {
+    use alloc::boxed::Box;
+
+    use futures::task::noop_waker_ref;
+
+    let semaphore = Semaphore::new(1);
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let permit = semaphore.try_acquire().unwrap();
+    let mut waiter = Box::pin(semaphore.acquire());
+    assert!(waiter.as_mut().poll(&mut context).is_pending());
+
+    drop(permit);
+    semaphore.close();
+    drop(waiter);
+    assert_eq!(semaphore.available_permits(), 1);
+}
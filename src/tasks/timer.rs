@@ -0,0 +1,262 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::task::Waker;
+
+use spin::{Lazy, Mutex};
+
+use crate::platform::interrupts::without_interrupts;
+use crate::platform::smp::current_cpu;
+use crate::platform::time::{tick, ticks};
+
+/// Local APIC IDs fit in a byte
+const MAX_CPUS: usize = 256;
+
+/// Timers of the timer interrupt
+static TIMERS: Lazy<TimerWheel> = Lazy::new(TimerWheel::new);
+
+/// Virtual clock of the task running on each core, null for the timer
+/// interrupt. Set by a deterministic executor while it polls.
+const NO_CLOCK: AtomicPtr<VirtualClock> = AtomicPtr::new(ptr::null_mut());
+static SCOPES: [AtomicPtr<VirtualClock>; MAX_CPUS] = [NO_CLOCK; MAX_CPUS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Timers ordered by deadline, the ID keeps equal deadlines apart
+struct TimerWheel {
+    timers: Mutex<BTreeMap<(u64, TimerId), Waker>>,
+    /// Earliest deadline in `timers`, so most ticks don't need to take the lock
+    next_deadline: AtomicU64,
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            timers: Mutex::new(BTreeMap::new()),
+            next_deadline: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn register(&self, deadline: u64, waker: Waker) -> TimerId {
+        let id = TimerId::new();
+
+        // The timer interrupt takes the same lock
+        without_interrupts(|| {
+            self.timers.lock().insert((deadline, id), waker);
+            self.next_deadline.fetch_min(deadline, Ordering::AcqRel);
+        });
+        id
+    }
+
+    fn update(&self, deadline: u64, id: TimerId, waker: &Waker) -> bool {
+        without_interrupts(|| match self.timers.lock().get_mut(&(deadline, id)) {
+            Some(current) => {
+                if !current.will_wake(waker) {
+                    *current = waker.clone();
+                }
+                true
+            },
+            None => false,
+        })
+    }
+
+    fn cancel(&self, deadline: u64, id: TimerId) {
+        without_interrupts(|| {
+            self.timers.lock().remove(&(deadline, id));
+        });
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        match self.next_deadline.load(Ordering::Acquire) {
+            u64::MAX => None,
+            deadline => Some(deadline),
+        }
+    }
+
+    /// Wake every timer whose deadline is at or before `now`. Must run with
+    /// interrupts disabled.
+    fn wake_expired(&self, now: u64) {
+        if now < self.next_deadline.load(Ordering::Acquire) {
+            return;
+        }
+
+        let mut timers = self.timers.lock();
+        while let Some(&(deadline, id)) = timers.keys().next() {
+            if deadline > now {
+                break;
+            }
+            if let Some(waker) = timers.remove(&(deadline, id)) {
+                waker.wake();
+            }
+        }
+
+        let next = timers.keys().next().map_or(u64::MAX, |(deadline, _)| *deadline);
+        self.next_deadline.store(next, Ordering::Release);
+    }
+}
+
+/// Time of a deterministic executor. Only the tasks it polls see it, and it
+/// only moves when the executor jumps it to the earliest of its timers.
+pub struct VirtualClock {
+    now: AtomicU64,
+    timers: TimerWheel,
+}
+
+impl VirtualClock {
+    /// A clock starting at the current tick count
+    pub fn new() -> Self {
+        Self {
+            now: AtomicU64::new(ticks()),
+            timers: TimerWheel::new(),
+        }
+    }
+
+    /// Jump to the earliest pending timer and fire it. Returns `false` if
+    /// there are no timers.
+    pub fn advance(&self) -> bool {
+        match self.timers.next_deadline() {
+            Some(deadline) => {
+                let now = self.now.fetch_max(deadline, Ordering::AcqRel).max(deadline);
+                without_interrupts(|| self.timers.wake_expired(now));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Make this the clock of the code running on this core until the guard
+    /// is dropped
+    pub fn enter(self: &Arc<Self>) -> ClockGuard {
+        let scope = &SCOPES[current_cpu() as usize % MAX_CPUS];
+        let previous = scope.swap(Arc::as_ptr(self) as *mut VirtualClock, Ordering::AcqRel);
+        ClockGuard { previous }
+    }
+}
+
+/// Restores the clock that was in use before [`VirtualClock::enter`]
+#[must_use]
+pub struct ClockGuard {
+    previous: *mut VirtualClock,
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        SCOPES[current_cpu() as usize % MAX_CPUS].store(self.previous, Ordering::Release);
+    }
+}
+
+/// Source of time and timers of a task
+#[derive(Clone)]
+pub enum Clock {
+    /// Counted by the timer interrupt
+    Timer,
+    Virtual(Arc<VirtualClock>),
+}
+
+impl Clock {
+    /// Clock of the task running on this core
+    pub fn current() -> Self {
+        let clock = SCOPES[current_cpu() as usize % MAX_CPUS].load(Ordering::Acquire);
+        if clock.is_null() {
+            return Clock::Timer;
+        }
+
+        // The executor that entered the clock keeps it alive while it polls
+        unsafe {
+            Arc::increment_strong_count(clock);
+            Clock::Virtual(Arc::from_raw(clock))
+        }
+    }
+
+    /// Ticks counted by this clock
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::Timer => ticks(),
+            Clock::Virtual(clock) => clock.now.load(Ordering::Acquire),
+        }
+    }
+
+    /// Whether `deadline` has already passed
+    pub fn is_expired(&self, deadline: u64) -> bool {
+        self.now() >= deadline
+    }
+
+    /// Wake `waker` once the tick count reaches `deadline`
+    pub fn register(&self, deadline: u64, waker: Waker) -> TimerId {
+        self.timers().register(deadline, waker)
+    }
+
+    /// Replace the waker of a pending timer, returns `false` if it already
+    /// fired
+    pub fn update(&self, deadline: u64, id: TimerId, waker: &Waker) -> bool {
+        self.timers().update(deadline, id, waker)
+    }
+
+    pub fn cancel(&self, deadline: u64, id: TimerId) {
+        self.timers().cancel(deadline, id)
+    }
+
+    fn timers(&self) -> &TimerWheel {
+        match self {
+            Clock::Timer => &TIMERS,
+            Clock::Virtual(clock) => &clock.timers,
+        }
+    }
+}
+
+/// Timer interrupt handler, wakes every task whose deadline has passed.
+/// Virtual clocks don't see it.
+///
+/// Must run with interrupts disabled.
+pub fn on_timer_interrupt() {
+    TIMERS.wake_expired(tick());
+}
+
+#[test_case]
+fn test_virtual_clock_is_scoped() {
+    use futures::task::noop_waker;
+
+    let clock = Arc::new(VirtualClock::new());
+    let start = clock.now.load(Ordering::Acquire);
+    {
+        let _guard = clock.enter();
+        let current = Clock::current();
+        assert!(matches!(current, Clock::Virtual(_)));
+        current.register(start + 1000, noop_waker());
+    }
+    assert!(matches!(Clock::current(), Clock::Timer));
+    assert!(clock.advance());
+    assert_eq!(Clock::Virtual(clock.clone()).now(), start + 1000);
+    assert!(!clock.advance());
+}
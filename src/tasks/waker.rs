@@ -24,26 +24,34 @@ use alloc::sync::Arc;
 use alloc::task::Wake;
 use core::task::Waker;
 
-use crossbeam_queue::SegQueue;
-
-use super::TaskId;
+use super::info::TaskInfo;
+use super::queue::ReadyQueues;
+use super::smp;
 
 pub struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<SegQueue<TaskId>>,
+    info: Arc<TaskInfo>,
+    task_queue: Arc<ReadyQueues>,
 }
 
 impl TaskWaker {
-    pub fn new(task_id: TaskId, task_queue: Arc<SegQueue<TaskId>>) -> Self {
-        TaskWaker { task_id, task_queue }
+    pub fn new(info: Arc<TaskInfo>, task_queue: Arc<ReadyQueues>) -> Self {
+        TaskWaker { info, task_queue }
     }
 
-    pub fn task_waker(task_id: TaskId, task_queue: Arc<SegQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(Self::new(task_id, task_queue)))
+    pub fn task_waker(info: Arc<TaskInfo>, task_queue: Arc<ReadyQueues>) -> Waker {
+        Waker::from(Arc::new(Self::new(info, task_queue)))
     }
 
+    /// Queue the task with its current priority, on the core it moved to if
+    /// another core stole it
     pub fn wake_task(&self) {
-        self.task_queue.push(self.task_id);
+        self.info.record_wake();
+        let (task_id, priority) = (self.info.id(), self.info.priority.get());
+
+        let cpu = self.info.cpu();
+        if cpu == self.task_queue.cpu() || !smp::push(cpu, task_id, priority) {
+            self.task_queue.push(task_id, priority);
+        }
     }
 }
 
@@ -0,0 +1,311 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use chrono::Duration;
+use spin::Lazy;
+
+use super::info::{self, TaskInfo};
+use super::park::interval;
+use super::TaskId;
+use crate::platform::smp::{self, current_cpu};
+use crate::platform::time::{self, duration_to_ticks, ticks_to_duration};
+use crate::prelude::*;
+
+/// Local APIC IDs fit in a byte
+const MAX_CPUS: usize = 256;
+
+/// Longest a single poll may run before it is reported
+const DEFAULT_THRESHOLD_MS: i64 = 100;
+/// How often [`run`] logs the overruns the interrupt handlers recorded
+const REPORT_PERIOD_MS: i64 = 50;
+
+static THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_MS as u64 * time::TIMER_FREQUENCY / 1000);
+static PANIC_ON_STALL: AtomicBool = AtomicBool::new(false);
+
+/// Poll running on each core
+static POLLS: Lazy<Vec<PollSlot>> = Lazy::new(|| (0..MAX_CPUS).map(|_| PollSlot::new()).collect());
+
+struct PollSlot {
+    /// Task being polled, null between polls. It stays alive until the poll
+    /// finishes, and only its own core dereferences it.
+    task: AtomicPtr<TaskInfo>,
+    start: AtomicU64,
+    reported: AtomicBool,
+    /// Overrun recorded by the interrupt handler and not logged yet. The
+    /// fields below describe it, they are only written while this is clear.
+    pending: AtomicBool,
+    overrun_task: AtomicU64,
+    overrun_location: AtomicPtr<Location<'static>>,
+    overrun_ticks: AtomicU64,
+    overrun_instruction: AtomicU64,
+}
+
+impl PollSlot {
+    fn new() -> Self {
+        PollSlot {
+            task: AtomicPtr::new(ptr::null_mut()),
+            start: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            overrun_task: AtomicU64::new(0),
+            overrun_location: AtomicPtr::new(ptr::null_mut()),
+            overrun_ticks: AtomicU64::new(0),
+            overrun_instruction: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Report polls running longer than `threshold`, panicking if `panic` is set
+pub fn configure(threshold: Duration, panic: bool) {
+    THRESHOLD.store(duration_to_ticks(threshold).max(1), Ordering::Relaxed);
+    PANIC_ON_STALL.store(panic, Ordering::Relaxed);
+}
+
+fn slot() -> &'static PollSlot {
+    &POLLS[current_cpu() as usize % MAX_CPUS]
+}
+
+/// Poll that was running on a core when a nested executor, e.g. one blocked on
+/// by a task, started polling
+pub(super) struct OuterPoll {
+    task: *mut TaskInfo,
+    start: u64,
+    reported: bool,
+}
+
+/// Called by the executor right before polling `task`. The returned poll is
+/// restored by [`poll_finished`].
+pub(super) fn poll_started(task: &TaskInfo) -> OuterPoll {
+    let slot = slot();
+    let outer = OuterPoll {
+        task: slot.task.load(Ordering::Acquire),
+        start: slot.start.load(Ordering::Relaxed),
+        reported: slot.reported.load(Ordering::Relaxed),
+    };
+
+    slot.start.store(time::ticks(), Ordering::Relaxed);
+    slot.reported.store(false, Ordering::Relaxed);
+    slot.task
+        .store(task as *const TaskInfo as *mut TaskInfo, Ordering::Release);
+    outer
+}
+
+/// Called by the executor once the poll returned
+pub(super) fn poll_finished(outer: OuterPoll) {
+    let slot = slot();
+    slot.start.store(outer.start, Ordering::Relaxed);
+    slot.reported.store(outer.reported, Ordering::Relaxed);
+    slot.task.store(outer.task, Ordering::Release);
+}
+
+/// Whether the poll in `slot` overran the threshold and wasn't reported yet
+fn overran(slot: &PollSlot) -> bool {
+    if slot.task.load(Ordering::Acquire).is_null() || slot.reported.load(Ordering::Relaxed) {
+        return false;
+    }
+    let elapsed = time::ticks().saturating_sub(slot.start.load(Ordering::Relaxed));
+    elapsed >= THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Check the polls of all cores, from the timer interrupt. Only one core
+/// receives it, so a core whose poll overran is sent a wake-up IPI and
+/// reports the poll itself in [`on_wakeup_interrupt`], while the task is
+/// known to be alive. `instruction_pointer` is where the timer interrupted.
+pub fn on_timer_interrupt(instruction_pointer: u64) {
+    let current = current_cpu() as usize % MAX_CPUS;
+    for (cpu, slot) in POLLS.iter().enumerate() {
+        if cpu == current {
+            check(slot, instruction_pointer);
+        } else if overran(slot) {
+            smp::wake_cpu(cpu as u32);
+        }
+    }
+}
+
+/// Check the poll of the executing core, `instruction_pointer` is where the
+/// wake-up IPI interrupted it
+pub fn on_wakeup_interrupt(instruction_pointer: u64) {
+    check(slot(), instruction_pointer);
+}
+
+/// Record the poll in `slot`, which must belong to the executing core, if it
+/// overran. This runs in an interrupt handler, maybe on top of a poll holding
+/// the allocator or logger lock, so it neither allocates nor logs: [`run`]
+/// logs the overrun later. Only a panic, with the poll possibly never
+/// returning, can't wait for that.
+fn check(slot: &PollSlot, instruction_pointer: u64) {
+    if !overran(slot) {
+        return;
+    }
+    let elapsed = time::ticks().saturating_sub(slot.start.load(Ordering::Relaxed));
+    slot.reported.store(true, Ordering::Relaxed);
+
+    // The poll is still running underneath this handler, so the task is alive
+    let task = unsafe { &*slot.task.load(Ordering::Acquire) };
+    if PANIC_ON_STALL.load(Ordering::Relaxed) {
+        panic!(
+            "watchdog: task {} ({}) spawned at {} has been polled for {} ms on cpu {}, interrupted at {:#x}",
+            task.id().as_u64(),
+            task.name().unwrap_or("unnamed"),
+            task.location(),
+            ticks_to_duration(elapsed).num_milliseconds(),
+            current_cpu(),
+            instruction_pointer
+        );
+    }
+
+    // An overrun that wasn't logged yet is kept, later ones are dropped
+    if slot.pending.load(Ordering::Acquire) {
+        return;
+    }
+    slot.overrun_task.store(task.id().as_u64(), Ordering::Relaxed);
+    let location = task.location() as *const Location<'static> as *mut Location<'static>;
+    slot.overrun_location.store(location, Ordering::Relaxed);
+    slot.overrun_ticks.store(elapsed, Ordering::Relaxed);
+    slot.overrun_instruction
+        .store(instruction_pointer, Ordering::Relaxed);
+    slot.pending.store(true, Ordering::Release);
+}
+
+/// Log the overruns recorded since the last call, returning how many
+pub fn report() -> usize {
+    POLLS
+        .iter()
+        .enumerate()
+        .filter(|(cpu, slot)| report_slot(*cpu, slot))
+        .count()
+}
+
+/// Log the overrun recorded in `slot` of `cpu`, if any
+fn report_slot(cpu: usize, slot: &PollSlot) -> bool {
+    if !slot.pending.load(Ordering::Acquire) {
+        return false;
+    }
+    let id = TaskId(slot.overrun_task.load(Ordering::Relaxed));
+    let location = unsafe { &*slot.overrun_location.load(Ordering::Relaxed) };
+    let elapsed = ticks_to_duration(slot.overrun_ticks.load(Ordering::Relaxed));
+    let instruction_pointer = slot.overrun_instruction.load(Ordering::Relaxed);
+    slot.pending.store(false, Ordering::Release);
+
+    // Looked up here, the handler can't copy the name without allocating
+    let name = info::find(id).and_then(|task| task.name);
+    error!(
+        "watchdog: task {} ({}) spawned at {} has been polled for {} ms on cpu {}, interrupted at {:#x}",
+        id.as_u64(),
+        name.as_deref().unwrap_or("unnamed"),
+        location,
+        elapsed.num_milliseconds(),
+        cpu,
+        instruction_pointer
+    );
+    true
+}
+
+/// Log overruns as they are recorded. Spawned once at boot, any core may run
+/// it, so a core stuck in a poll is reported by another.
+pub async fn run() {
+    let mut ticks = interval(Duration::milliseconds(REPORT_PERIOD_MS));
+    loop {
+        ticks.tick().await;
+        report();
+    }
+}
+
+#[test_case]
+fn test_overrun_reported_once() {
+    use super::Priority;
+
+    let info = TaskInfo::new(TaskId::new(), None, Priority::default(), Location::caller());
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    configure(Duration::milliseconds(1), false);
+
+    let outer = poll_started(&info);
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        core::hint::spin_loop();
+    }
+
+    // Reported by the timer interrupt, or by this call
+    on_timer_interrupt(0);
+    assert!(slot().reported.load(Ordering::Relaxed));
+    assert!(!overran(slot()));
+
+    poll_finished(outer);
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_nested_poll_restores_outer() {
+    use super::Priority;
+
+    let outer = TaskInfo::new(TaskId::new(), None, Priority::default(), Location::caller());
+    let inner = TaskInfo::new(TaskId::new(), None, Priority::default(), Location::caller());
+
+    let before = slot().task.load(Ordering::Acquire);
+    let previous = poll_started(&outer);
+    let outer_start = slot().start.load(Ordering::Relaxed);
+    let outer_poll = poll_started(&inner);
+    poll_finished(outer_poll);
+
+    assert_eq!(
+        slot().task.load(Ordering::Acquire),
+        &outer as *const TaskInfo as *mut TaskInfo
+    );
+    assert_eq!(slot().start.load(Ordering::Relaxed), outer_start);
+    poll_finished(previous);
+    assert_eq!(slot().task.load(Ordering::Acquire), before);
+}
+
+#[test_case]
+fn test_overrun_logged_from_task_context() {
+    use super::Priority;
+
+    let info = TaskInfo::new(TaskId::new(), None, Priority::default(), Location::caller());
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    configure(Duration::milliseconds(1), false);
+
+    // A slot of its own, so neither the timer nor the watchdog task touch it
+    let slot = PollSlot::new();
+    slot.task
+        .store(&info as *const TaskInfo as *mut TaskInfo, Ordering::Release);
+    slot.start
+        .store(time::ticks().saturating_sub(2), Ordering::Relaxed);
+
+    check(&slot, 0x1000);
+    assert!(slot.pending.load(Ordering::Acquire));
+    assert_eq!(slot.overrun_task.load(Ordering::Relaxed), info.id().as_u64());
+    assert_eq!(slot.overrun_instruction.load(Ordering::Relaxed), 0x1000);
+
+    // Recorded once, and logged once
+    check(&slot, 0x2000);
+    assert_eq!(slot.overrun_instruction.load(Ordering::Relaxed), 0x1000);
+    assert!(report_slot(0, &slot));
+    assert!(!slot.pending.load(Ordering::Acquire));
+    assert!(!report_slot(0, &slot));
+
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+}
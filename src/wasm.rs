@@ -20,23 +20,256 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+mod externals;
+mod futex;
 mod modules;
+pub mod sip;
+pub mod supervisor;
 
-use wasmi::{Error, ImportsBuilder, Module, ModuleInstance, NopExternals};
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use futures::future::{select, Either};
+use futures::pin_mut;
+use wasmi::{Error, ExternVal, ImportsBuilder, ModuleInstance};
+
+use self::externals::SipExternals;
+use self::modules::etheryal::EtheryalImportResolver;
+use self::modules::metering::{self, MeteringImportResolver, METERING_MODULE};
 use self::modules::wasi::WasiImportResolver;
+use self::sip::{Killed, SipHandle};
+use crate::audit::{self, AuditEvent};
+use crate::memory::accounting::{self, Tag};
+use crate::prelude::*;
+
+/// Identifier of a software-isolated process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SipId(u64);
+
+impl SipId {
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        SipId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_u64(id: u64) -> Self {
+        SipId(id)
+    }
+}
 
 /// Run a Webassembly program
 pub async fn run_program(buff: &[u8]) -> Result<(), Error> {
-    let module = Module::from_buffer(buff)?;
+    run_sip(SipId::new(), buff).await
+}
+
+/// Run a Webassembly program as the SIP `sip_id`, until it exits or it is
+/// killed through [`sip::kill`] or [`sip::request_termination`]
+pub async fn run_sip(sip_id: SipId, buff: &[u8]) -> Result<(), Error> {
+    let sip = SipHandle::register(sip_id);
+    let watchdog = Box::pin(sip.control().watchdog());
+
+    // Dropping the program future at the end of this block releases the
+    // instance, its memory and all host state owned by the SIP
+    let result = {
+        let program = accounting::tagged(Tag::Sip(sip_id), execute(&sip, buff));
+        pin_mut!(program);
+        match select(program, watchdog).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => {
+                warn!("Killed SIP {:?}", sip.id());
+                Err(Error::Host(Box::new(Killed)))
+            },
+        }
+    };
+
+    // Also dropped last when this future is dropped before it completes
+    drop(sip);
+    result
+}
+
+async fn execute(sip: &SipHandle, buff: &[u8]) -> Result<(), Error> {
+    // Metered code yields to the executor now and then, so a SIP that never
+    // makes a blocking host call can't hold its core
+    let object = format!(
+        "module of {} bytes, hash {:016x}",
+        buff.len(),
+        audit::module_hash(buff)
+    );
+    let module = metering::load_metered(buff).map_err(|error| reject(sip, &object, error))?;
     let mut import_resolver = ImportsBuilder::default();
 
     // Setup default modules
-    let wasi_resolver = WasiImportResolver::new();
+    let wasi_resolver = WasiImportResolver::new(sip.id());
     import_resolver.push_resolver("wasi_snapshot_preview1", &wasi_resolver);
+    let etheryal_resolver = EtheryalImportResolver::new(sip.id());
+    import_resolver.push_resolver("etheryal", &etheryal_resolver);
+    let metering_resolver = MeteringImportResolver::new(sip.id());
+    import_resolver.push_resolver(METERING_MODULE, &metering_resolver);
+
+    let instance =
+        ModuleInstance::new(&module, &import_resolver).map_err(|error| reject(sip, &object, error))?;
+    audit::record(sip.id(), AuditEvent::ModuleLoaded, &object);
+    let exports = instance.not_started_instance();
+    let memory = exports
+        .export_by_name("memory")
+        .and_then(|export| export.as_memory().cloned());
+    let signal_hook = exports
+        .export_by_name("_etheryal_on_signal")
+        .and_then(|export| export.as_func().cloned());
+    let mut externals = SipExternals::new(sip.id(), sip.control().clone(), memory, signal_hook);
 
-    ModuleInstance::new(&module, &import_resolver)?
-        .async_run_start(&mut NopExternals, 10)
-        .await?;
+    let instance = instance.async_run_start(&mut externals, 10).await?;
+    if let Some(ExternVal::Func(entry)) = instance.export_by_name("_start") {
+        externals.run(&entry).await?;
+    }
     Ok(())
 }
+
+/// Log a module that failed to load and pass the error on
+fn reject(sip: &SipHandle, object: &str, error: Error) -> Error {
+    audit::record(
+        sip.id(),
+        AuditEvent::ModuleRejected,
+        &format!("{}: {}", object, error),
+    );
+    error
+}
+
+/// `(func (export "_start") (loop (br 0)))`, never makes a host call
+#[cfg(test)]
+static SPIN_MODULE: [u8; 41] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // Header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // Type section, [] -> []
+    0x03, 0x02, 0x01, 0x00, // Function section
+    0x07, 0x0a, 0x01, 0x06, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x00, // Export section
+    0x0a, 0x09, 0x01, 0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // Code section
+];
+
+/// Grows its exported memory by 2 GiB and traps unless that fails:
+/// `(memory (export "memory") 1)`
+/// `(func (export "_start") (if (i32.ne (memory.grow (i32.const 0x8000))
+/// (i32.const -1)) (unreachable)))`
+#[cfg(test)]
+static GROW_MODULE: [u8; 63] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // Header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // Type section, [] -> []
+    0x03, 0x02, 0x01, 0x00, // Function section
+    0x05, 0x03, 0x01, 0x00, 0x01, // Memory section, one page
+    0x07, 0x13, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x73, 0x74, 0x61,
+    0x72, 0x74, 0x00, 0x00, // Export section
+    0x0a, 0x11, 0x01, 0x0f, 0x00, 0x41, 0x80, 0x80, 0x02, 0x40, 0x00, 0x41, 0x7f, 0x47, 0x04, 0x40, 0x00,
+    0x0b, 0x0b, // Code section
+];
+
+#[test_case]
+fn test_memory_grow_fails_gracefully() {
+    use crate::tasks::executor::TaskExecutor;
+
+    let sip_id = SipId::new();
+    let result = TaskExecutor::deterministic(0).block_on(run_sip(sip_id, &GROW_MODULE));
+    assert!(result.is_ok());
+}
+
+#[test_case]
+fn test_kill_compute_bound_sip() {
+    use futures::future::join;
+
+    use crate::tasks::executor::TaskExecutor;
+    use crate::tasks::park::yield_now;
+
+    let sip_id = SipId::new();
+    let killer = async move {
+        // Let the program start spinning first
+        yield_now().await;
+        yield_now().await;
+        assert!(sip::kill(sip_id));
+    };
+
+    let mut executor = TaskExecutor::deterministic(0);
+    let (result, _) = executor.block_on(join(run_sip(sip_id, &SPIN_MODULE), killer));
+    assert!(matches!(result, Err(Error::Host(_))));
+}
+
+#[test_case]
+fn test_dropped_sip_is_released() {
+    use crate::tasks::executor::TaskExecutor;
+    use crate::tasks::park::yield_now;
+
+    let sip_id = SipId::new();
+    let preempt = async {
+        yield_now().await;
+        yield_now().await;
+    };
+
+    let mut executor = TaskExecutor::deterministic(0);
+    let program = executor.block_on(select(Box::pin(run_sip(sip_id, &SPIN_MODULE)), Box::pin(preempt)));
+    let program = match program {
+        Either::Right((_, program)) => program,
+        Either::Left(_) => panic!("spinning SIP exited"),
+    };
+    assert!(accounting::usage_of(Tag::Sip(sip_id)).is_some());
+
+    drop(program);
+    assert!(accounting::usage_of(Tag::Sip(sip_id)).is_none());
+    assert!(!sip::running().contains(&sip_id));
+}
+
+/// `(import "etheryal" "signal_poll" (func (result i32)))`
+/// `(func (export "_start") (loop (br 0)))`
+#[cfg(test)]
+static SPIN_WITH_IMPORT_MODULE: [u8; 71] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // Header
+    0x01, 0x08, 0x02, 0x60, 0x00, 0x01, 0x7f, 0x60, 0x00, 0x00, // Type section, [] -> [i32], [] -> []
+    0x02, 0x18, 0x01, 0x08, 0x65, 0x74, 0x68, 0x65, 0x72, 0x79, 0x61, 0x6c, 0x0b, 0x73, 0x69, 0x67, 0x6e,
+    0x61, 0x6c, 0x5f, 0x70, 0x6f, 0x6c, 0x6c, 0x00, 0x00, // Import section
+    0x03, 0x02, 0x01, 0x01, // Function section
+    0x07, 0x0a, 0x01, 0x06, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x01, // Export section
+    0x0a, 0x09, 0x01, 0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // Code section
+];
+
+#[test_case]
+fn test_dropped_sip_revokes_capabilities() {
+    use crate::tasks::executor::TaskExecutor;
+    use crate::tasks::park::yield_now;
+
+    let sip_id = SipId::new();
+    let preempt = async {
+        yield_now().await;
+        yield_now().await;
+    };
+
+    let mut executor = TaskExecutor::deterministic(0);
+    let program = executor.block_on(select(
+        Box::pin(run_sip(sip_id, &SPIN_WITH_IMPORT_MODULE)),
+        Box::pin(preempt),
+    ));
+    assert!(matches!(program, Either::Right(_)), "spinning SIP exited");
+    drop(program);
+
+    let revoked = audit::AUDIT_LOG
+        .query_sip(sip_id)
+        .into_iter()
+        .filter(|entry| entry.event == AuditEvent::CapabilityRevoked)
+        .map(|entry| entry.object)
+        .collect::<Vec<_>>();
+    assert_eq!(revoked, ["etheryal.signal_poll"]);
+}
+
+#[test_case]
+fn test_rejected_module_is_audited() {
+    use crate::tasks::executor::TaskExecutor;
+
+    let sip_id = SipId::new();
+    let result = TaskExecutor::deterministic(0).block_on(run_sip(sip_id, b"not a module"));
+    assert!(result.is_err());
+
+    let entries = audit::AUDIT_LOG.query_sip(sip_id);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].event, AuditEvent::ModuleRejected);
+    assert!(entries[0]
+        .object
+        .contains(&format!("{:016x}", audit::module_hash(b"not a module"))));
+}
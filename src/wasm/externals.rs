@@ -0,0 +1,258 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::sync::Arc;
+use core::fmt;
+
+use chrono::Duration;
+use futures::future::{poll_fn, select, Either};
+use wasmi::memory_units::{Bytes, Pages};
+use wasmi::{
+    Error, Externals, FuncInstance, FuncRef, HostError, MemoryRef, ResumableError, RuntimeArgs, RuntimeValue,
+    Trap, TrapKind,
+};
+
+use super::futex::WaitResult;
+use super::modules::etheryal::{NOTIFY_FUNC_INDEX, SIGNAL_POLL_FUNC_INDEX, WAIT_FUNC_INDEX};
+use super::modules::metering::{GAS_FUNC_INDEX, MEMORY_GROW_FUNC_INDEX};
+use super::sip::{Killed, SipControl};
+use super::{futex, SipId};
+use crate::prelude::*;
+use crate::tasks::park::yield_now;
+
+/// Instructions a SIP runs before it yields to the executor
+const FUEL_PER_SLICE: u64 = 100_000;
+
+/// Host calls that can't complete synchronously. The host function traps with
+/// one of these, the calling task awaits it and then resumes the program.
+#[derive(Debug)]
+pub enum HostCall {
+    FutexWait {
+        addr: u32,
+        expected: u32,
+        timeout: i64,
+    },
+    /// The program used up its fuel
+    Yield,
+}
+
+impl fmt::Display for HostCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl HostError for HostCall {}
+
+/// Host state of a running SIP
+pub struct SipExternals {
+    sip_id: SipId,
+    control: Arc<SipControl>,
+    memory: Option<MemoryRef>,
+    signal_hook: Option<FuncRef>,
+    /// Instructions left until the program yields, `None` while it can't
+    fuel: Option<u64>,
+}
+
+impl SipExternals {
+    pub fn new(
+        sip_id: SipId, control: Arc<SipControl>, memory: Option<MemoryRef>, signal_hook: Option<FuncRef>,
+    ) -> Self {
+        Self {
+            sip_id,
+            control,
+            memory,
+            signal_hook,
+            fuel: None,
+        }
+    }
+
+    /// Invoke `func`, parking the calling task whenever the program makes a
+    /// blocking host call or used up its fuel. The program stops at the next
+    /// such point once the SIP is killed.
+    pub async fn run(&mut self, func: &FuncRef) -> Result<Option<RuntimeValue>, Error> {
+        self.deliver_signals()?;
+        let mut invocation = FuncInstance::invoke_resumable(func, &[][..])?;
+        self.fuel = Some(FUEL_PER_SLICE);
+        let mut result = invocation.start_execution(self);
+
+        loop {
+            match result {
+                Ok(value) => return Ok(value),
+                Err(ResumableError::Trap(trap)) if invocation.is_resumable() => {
+                    let call = match trap.into_kind() {
+                        TrapKind::Host(error) => error.downcast::<HostCall>(),
+                        kind => return Err(Error::Trap(Trap::new(kind))),
+                    };
+                    let value = match call {
+                        Ok(call) => self.complete(*call).await?,
+                        Err(error) => return Err(Error::Trap(Trap::new(TrapKind::Host(error)))),
+                    };
+                    self.deliver_signals()?;
+                    result = invocation.resume_execution(value, self);
+                },
+                Err(ResumableError::Trap(trap)) => return Err(Error::Trap(trap)),
+                Err(error) => return Err(Error::Function(format!("{:?}", error))),
+            }
+        }
+    }
+
+    async fn complete(&mut self, call: HostCall) -> Result<Option<RuntimeValue>, Error> {
+        let value = match call {
+            HostCall::FutexWait {
+                addr,
+                expected,
+                timeout,
+            } => {
+                let memory = self.memory.as_ref().expect("Futex address was not validated.");
+                let timeout = Some(timeout)
+                    .filter(|timeout| *timeout >= 0)
+                    .map(Duration::nanoseconds);
+                let wait = Box::pin(futex::wait(self.sip_id, memory, addr, expected, timeout));
+                let signalled = poll_fn(|cx| self.control.poll_signals(cx));
+
+                let result = match select(wait, signalled).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => WaitResult::Interrupted,
+                };
+                Some(RuntimeValue::I32(result as i32))
+            },
+            HostCall::Yield => {
+                yield_now().await;
+                self.fuel = Some(FUEL_PER_SLICE);
+                None
+            },
+        };
+
+        if self.control.is_killed() {
+            return Err(Error::Host(Box::new(Killed)));
+        }
+        Ok(value)
+    }
+
+    /// Call the exported `_etheryal_on_signal` hook with the pending signals.
+    /// Programs without the hook have to use `signal_poll` instead.
+    ///
+    /// The hook runs to completion without yielding, so it must not make
+    /// blocking host calls and should return quickly.
+    fn deliver_signals(&mut self) -> Result<(), Error> {
+        let hook = match &self.signal_hook {
+            Some(hook) => hook.clone(),
+            None => return Ok(()),
+        };
+
+        let signals = self.control.take_signals();
+        if !signals.is_empty() {
+            let fuel = self.fuel.take();
+            let result = FuncInstance::invoke(&hook, &[RuntimeValue::I32(signals.bits() as i32)], self);
+            self.fuel = fuel;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// `memory.grow`, returning the previous size in pages or -1 if the memory
+    /// can't grow. Growing reallocates the linear memory, which may double its
+    /// capacity, so it fails unless the heap has room for that. Programs have
+    /// to export their memory to grow it, as WASI asks for anyway.
+    fn grow_memory(&self, pages: u32) -> i32 {
+        let memory = match &self.memory {
+            Some(memory) => memory,
+            None => return -1,
+        };
+        let current = Bytes::from(memory.current_size()).0;
+        let additional = Bytes::from(Pages(pages as usize)).0;
+        if !crate::memory::can_allocate((current + additional).max(2 * current)) {
+            warn!("SIP {:?} can't grow its memory by {} pages", self.sip_id, pages);
+            return -1;
+        }
+
+        match memory.grow(Pages(pages as usize)) {
+            Ok(previous) => previous.0 as i32,
+            Err(_) => -1,
+        }
+    }
+
+    /// Futex addresses must be 4-byte aligned and inside the linear memory
+    fn check_futex_address(&self, addr: u32) -> Result<(), Trap> {
+        let in_bounds = self
+            .memory
+            .as_ref()
+            .map_or(false, |memory| memory.get_value::<u32>(addr).is_ok());
+
+        if addr % 4 == 0 && in_bounds {
+            Ok(())
+        } else {
+            Err(TrapKind::MemoryAccessOutOfBounds.into())
+        }
+    }
+}
+
+impl Externals for SipExternals {
+    fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            WAIT_FUNC_INDEX => {
+                let addr: u32 = args.nth_checked(0)?;
+                let expected: u32 = args.nth_checked(1)?;
+                let timeout: i64 = args.nth_checked(2)?;
+                self.check_futex_address(addr)?;
+
+                Err(TrapKind::Host(Box::new(HostCall::FutexWait {
+                    addr,
+                    expected,
+                    timeout,
+                }))
+                .into())
+            },
+            NOTIFY_FUNC_INDEX => {
+                let addr: u32 = args.nth_checked(0)?;
+                let count: u32 = args.nth_checked(1)?;
+                self.check_futex_address(addr)?;
+
+                let woken = futex::notify(self.sip_id, addr, count);
+                Ok(Some(RuntimeValue::I32(woken as i32)))
+            },
+            SIGNAL_POLL_FUNC_INDEX => {
+                let signals = self.control.take_signals();
+                Ok(Some(RuntimeValue::I32(signals.bits() as i32)))
+            },
+            GAS_FUNC_INDEX => {
+                let cost: u32 = args.nth_checked(0)?;
+                match &mut self.fuel {
+                    Some(fuel) if *fuel <= u64::from(cost) => {
+                        Err(TrapKind::Host(Box::new(HostCall::Yield)).into())
+                    },
+                    Some(fuel) => {
+                        *fuel -= u64::from(cost);
+                        Ok(None)
+                    },
+                    None => Ok(None),
+                }
+            },
+            MEMORY_GROW_FUNC_INDEX => {
+                let pages: u32 = args.nth_checked(0)?;
+                Ok(Some(RuntimeValue::I32(self.grow_memory(pages))))
+            },
+            _ => Err(TrapKind::Unreachable.into()),
+        }
+    }
+}
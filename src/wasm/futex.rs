@@ -0,0 +1,249 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use chrono::Duration;
+use futures::future::{select, Either};
+use futures::task::AtomicWaker;
+use spin::{Lazy, Mutex};
+use wasmi::MemoryRef;
+
+use super::SipId;
+use crate::memory::accounting::{self, Tag};
+use crate::prelude::*;
+use crate::tasks::park::sleep;
+
+type WaitQueue = VecDeque<Arc<Waiter>>;
+
+static FUTEXES: Lazy<Mutex<BTreeMap<FutexKey, WaitQueue>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Outcome of a wait, using the same codes as `memory.atomic.wait32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    Woken = 0,
+    NotEqual = 1,
+    TimedOut = 2,
+    /// A signal arrived while waiting
+    Interrupted = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct FutexKey {
+    sip_id: SipId,
+    addr: u32,
+}
+
+struct Waiter {
+    woken: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Waiter {
+    fn new() -> Self {
+        Self {
+            woken: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn wake(&self) {
+        self.woken.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
+
+/// Park the calling task until `addr` is notified, as long as the 32-bit
+/// value stored there is still `expected`.
+///
+/// The comparison and the registration happen under the futex table lock, so
+/// a notify can't be lost between them. A `timeout` of `None` waits forever.
+pub async fn wait(
+    sip_id: SipId, memory: &MemoryRef, addr: u32, expected: u32, timeout: Option<Duration>,
+) -> WaitResult {
+    let key = FutexKey { sip_id, addr };
+    let waiter = {
+        let mut futexes = FUTEXES.lock();
+        match memory.get_value::<u32>(addr) {
+            Ok(value) if value == expected => {},
+            _ => return WaitResult::NotEqual,
+        }
+
+        accounting::with_tag(Tag::Wasm, || {
+            let waiter = Arc::new(Waiter::new());
+            futexes.entry(key).or_default().push_back(waiter.clone());
+            waiter
+        })
+    };
+    let wait = FutexWait { key, waiter };
+
+    match timeout {
+        Some(timeout) => match select(wait, Box::pin(sleep(timeout))).await {
+            Either::Left(_) => WaitResult::Woken,
+            // A notify may have raced with the deadline, it already counted us
+            Either::Right((_, wait)) if wait.waiter.is_woken() => WaitResult::Woken,
+            Either::Right(_) => WaitResult::TimedOut,
+        },
+        None => {
+            wait.await;
+            WaitResult::Woken
+        },
+    }
+}
+
+/// Wake up to `count` tasks waiting on `addr`, in FIFO order. Returns the
+/// number of woken tasks.
+pub fn notify(sip_id: SipId, addr: u32, count: u32) -> u32 {
+    let key = FutexKey { sip_id, addr };
+    let mut futexes = FUTEXES.lock();
+    let mut woken = 0;
+
+    if let Some(queue) = futexes.get_mut(&key) {
+        while woken < count {
+            match queue.pop_front() {
+                Some(waiter) => waiter.wake(),
+                None => break,
+            }
+            woken += 1;
+        }
+
+        if queue.is_empty() {
+            futexes.remove(&key);
+        }
+    }
+    woken
+}
+
+struct FutexWait {
+    key: FutexKey,
+    waiter: Arc<Waiter>,
+}
+
+impl Future for FutexWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register before checking, so a wake between both steps isn't missed
+        self.waiter.waker.register(cx.waker());
+
+        if self.waiter.is_woken() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for FutexWait {
+    fn drop(&mut self) {
+        if self.waiter.is_woken() {
+            return;
+        }
+
+        // Cancelled or timed out, leave the queue
+        let mut futexes = FUTEXES.lock();
+        if let Some(queue) = futexes.get_mut(&self.key) {
+            queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+
+            if queue.is_empty() {
+                futexes.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_notify_without_waiters() {
+    assert_eq!(notify(SipId::new(), 0, u32::MAX), 0);
+}
+
+#[cfg(test)]
+fn test_memory() -> MemoryRef {
+    use wasmi::memory_units::Pages;
+    use wasmi::MemoryInstance;
+
+    MemoryInstance::alloc(Pages(1), None).unwrap()
+}
+
+#[test_case]
+fn test_wait_value_mismatch() {
+    use futures::task::noop_waker_ref;
+
+    let memory = test_memory();
+    memory.set_value(8, 1u32).unwrap();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let mut wait = Box::pin(wait(SipId::new(), &memory, 8, 0, None));
+    assert_eq!(
+        wait.as_mut().poll(&mut context),
+        Poll::Ready(WaitResult::NotEqual)
+    );
+}
+
+#[test_case]
+fn test_wait_notify() {
+    use futures::task::noop_waker_ref;
+
+    let memory = test_memory();
+    let sip_id = SipId::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let mut first = Box::pin(wait(sip_id, &memory, 8, 0, None));
+    let mut second = Box::pin(wait(sip_id, &memory, 8, 0, None));
+    assert!(first.as_mut().poll(&mut context).is_pending());
+    assert!(second.as_mut().poll(&mut context).is_pending());
+
+    // Waiters are woken in the order they started waiting
+    assert_eq!(notify(sip_id, 8, 1), 1);
+    assert_eq!(first.as_mut().poll(&mut context), Poll::Ready(WaitResult::Woken));
+    assert!(second.as_mut().poll(&mut context).is_pending());
+
+    // Other addresses and SIPs have queues of their own
+    assert_eq!(notify(sip_id, 12, 1), 0);
+    assert_eq!(notify(SipId::new(), 8, 1), 0);
+
+    assert_eq!(notify(sip_id, 8, u32::MAX), 1);
+    assert_eq!(second.as_mut().poll(&mut context), Poll::Ready(WaitResult::Woken));
+}
+
+#[test_case]
+fn test_cancelled_wait_leaves_queue() {
+    use futures::task::noop_waker_ref;
+
+    let memory = test_memory();
+    let sip_id = SipId::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let mut wait = Box::pin(wait(sip_id, &memory, 8, 0, None));
+    assert!(wait.as_mut().poll(&mut context).is_pending());
+    drop(wait);
+    assert_eq!(notify(sip_id, 8, 1), 0);
+}
@@ -0,0 +1,108 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use wasmi::{
+    Error, FuncInstance, FuncRef, GlobalDescriptor, GlobalRef, MemoryDescriptor, MemoryRef,
+    ModuleImportResolver, Signature, TableDescriptor, TableRef, ValueType,
+};
+
+use crate::audit::{self, AuditEvent};
+use crate::prelude::*;
+use crate::wasm::SipId;
+
+/// `wait(addr: i32, expected: i32, timeout: i64) -> i32`
+pub const WAIT_FUNC_INDEX: usize = 0;
+/// `notify(addr: i32, count: i32) -> i32`
+pub const NOTIFY_FUNC_INDEX: usize = 1;
+/// `signal_poll() -> i32`
+pub const SIGNAL_POLL_FUNC_INDEX: usize = 2;
+
+/// Kernel specific host functions, imported from the `etheryal` module
+pub struct EtheryalImportResolver {
+    sip_id: SipId,
+}
+
+impl EtheryalImportResolver {
+    pub fn new(sip_id: SipId) -> Self {
+        Self { sip_id }
+    }
+
+    fn signature(index: usize) -> Signature {
+        match index {
+            WAIT_FUNC_INDEX => Signature::new(
+                &[ValueType::I32, ValueType::I32, ValueType::I64][..],
+                Some(ValueType::I32),
+            ),
+            NOTIFY_FUNC_INDEX => Signature::new(&[ValueType::I32, ValueType::I32][..], Some(ValueType::I32)),
+            SIGNAL_POLL_FUNC_INDEX => Signature::new(&[][..], Some(ValueType::I32)),
+            _ => unreachable!("Unknown etheryal function {}", index),
+        }
+    }
+
+    fn deny<T>(&self, field_name: &str) -> Result<T, Error> {
+        audit::record(
+            self.sip_id,
+            AuditEvent::CapabilityDenied,
+            &format!("etheryal.{}", field_name),
+        );
+        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+    }
+}
+
+impl ModuleImportResolver for EtheryalImportResolver {
+    /// Resolve a function.
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+        let object = format!("etheryal.{}", field_name);
+        let index = match field_name {
+            "wait" => WAIT_FUNC_INDEX,
+            "notify" => NOTIFY_FUNC_INDEX,
+            "signal_poll" => SIGNAL_POLL_FUNC_INDEX,
+            _ => return self.deny(field_name),
+        };
+
+        let expected = Self::signature(index);
+        if signature.params() != expected.params() || signature.return_type() != expected.return_type() {
+            audit::record(self.sip_id, AuditEvent::ImportMismatch, &object);
+            return Err(Error::Instantiation(format!(
+                "Export {} has a bad signature",
+                field_name
+            )));
+        }
+        audit::record(self.sip_id, AuditEvent::CapabilityGranted, &object);
+        Ok(FuncInstance::alloc_host(expected, index))
+    }
+
+    /// Resolve a global variable.
+    fn resolve_global(&self, field_name: &str, _global_type: &GlobalDescriptor) -> Result<GlobalRef, Error> {
+        self.deny(field_name)
+    }
+
+    /// Resolve a memory.
+    fn resolve_memory(&self, field_name: &str, _memory_type: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+        self.deny(field_name)
+    }
+
+    /// Resolve a table.
+    fn resolve_table(&self, field_name: &str, _table_type: &TableDescriptor) -> Result<TableRef, Error> {
+        self.deny(field_name)
+    }
+}
@@ -0,0 +1,183 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use parity_wasm::builder;
+use parity_wasm::elements::{self, ImportCountType, Instruction, Internal};
+use pwasm_utils::rules::Set;
+use wasmi::{
+    Error, FuncInstance, FuncRef, GlobalDescriptor, GlobalRef, MemoryDescriptor, MemoryRef, Module,
+    ModuleImportResolver, Signature, TableDescriptor, TableRef, ValueType,
+};
+
+use crate::audit::{self, AuditEvent};
+use crate::prelude::*;
+use crate::wasm::SipId;
+
+/// Module the gas counter is imported from by metered code
+pub const METERING_MODULE: &str = "env";
+
+/// `gas(cost: i32)`, numbered after the etheryal functions
+pub const GAS_FUNC_INDEX: usize = 3;
+/// `memory_grow(pages: i32) -> i32`, replaces the `memory.grow` instruction
+pub const MEMORY_GROW_FUNC_INDEX: usize = 4;
+
+/// Load a module, metering its code: every block of instructions starts with
+/// a call to `env.gas` with the number of instructions in it. `memory.grow`
+/// calls `env.memory_grow` instead, which fails if the kernel heap has no
+/// room for the larger memory.
+pub fn load_metered(buff: &[u8]) -> Result<Module, Error> {
+    let module =
+        parity_wasm::deserialize_buffer(buff).map_err(|error| Error::Validation(error.to_string()))?;
+    let module = inject_memory_grow(module);
+    let module = pwasm_utils::inject_gas_counter(module, &Set::default())
+        .map_err(|_| Error::Validation("Module can't be metered".to_string()))?;
+    Module::from_parity_wasm_module(module)
+}
+
+/// Import `env.memory_grow` and call it in place of every `memory.grow`. The
+/// import comes after the other imported functions, so the index of every
+/// function defined by the module goes up by one.
+fn inject_memory_grow(module: elements::Module) -> elements::Module {
+    let grows = |module: &elements::Module| {
+        module.code_section().map_or(false, |code| {
+            code.bodies()
+                .iter()
+                .flat_map(|body| body.code().elements())
+                .any(|instruction| matches!(instruction, Instruction::GrowMemory(_)))
+        })
+    };
+    if !grows(&module) {
+        return module;
+    }
+
+    let mut module_builder = builder::from_module(module);
+    let signature = module_builder.push_signature(
+        builder::signature()
+            .with_param(elements::ValueType::I32)
+            .with_result(elements::ValueType::I32)
+            .build_sig(),
+    );
+    module_builder.push_import(
+        builder::import()
+            .module(METERING_MODULE)
+            .field("memory_grow")
+            .external()
+            .func(signature)
+            .build(),
+    );
+    let mut module = module_builder.build();
+    let grow_func = module.import_count(ImportCountType::Function) as u32 - 1;
+    let shift = |index: &mut u32| {
+        if *index >= grow_func {
+            *index += 1;
+        }
+    };
+
+    if let Some(code) = module.code_section_mut() {
+        for body in code.bodies_mut() {
+            for instruction in body.code_mut().elements_mut() {
+                match instruction {
+                    Instruction::Call(index) => shift(index),
+                    Instruction::GrowMemory(_) => *instruction = Instruction::Call(grow_func),
+                    _ => {},
+                }
+            }
+        }
+    }
+    if let Some(exports) = module.export_section_mut() {
+        for export in exports.entries_mut() {
+            if let Internal::Function(index) = export.internal_mut() {
+                shift(index);
+            }
+        }
+    }
+    if let Some(elements) = module.elements_section_mut() {
+        for segment in elements.entries_mut() {
+            segment.members_mut().iter_mut().for_each(shift);
+        }
+    }
+    if let Some(mut start) = module.start_section() {
+        shift(&mut start);
+        module.set_start_section(start);
+    }
+    module
+}
+
+/// Resolves the functions metered code imports from `env`
+pub struct MeteringImportResolver {
+    sip_id: SipId,
+}
+
+impl MeteringImportResolver {
+    pub fn new(sip_id: SipId) -> Self {
+        Self { sip_id }
+    }
+
+    fn deny<T>(&self, field_name: &str) -> Result<T, Error> {
+        audit::record(
+            self.sip_id,
+            AuditEvent::CapabilityDenied,
+            &format!("{}.{}", METERING_MODULE, field_name),
+        );
+        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+    }
+}
+
+impl ModuleImportResolver for MeteringImportResolver {
+    /// Resolve a function.
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+        let (expected, index) = match field_name {
+            "gas" => (Signature::new(&[ValueType::I32][..], None), GAS_FUNC_INDEX),
+            "memory_grow" => (
+                Signature::new(&[ValueType::I32][..], Some(ValueType::I32)),
+                MEMORY_GROW_FUNC_INDEX,
+            ),
+            _ => return self.deny(field_name),
+        };
+        let matches =
+            signature.params() == expected.params() && signature.return_type() == expected.return_type();
+        if !matches {
+            let object = format!("{}.{}", METERING_MODULE, field_name);
+            audit::record(self.sip_id, AuditEvent::ImportMismatch, &object);
+            return Err(Error::Instantiation(format!(
+                "Export {} has a bad signature",
+                field_name
+            )));
+        }
+        Ok(FuncInstance::alloc_host(expected, index))
+    }
+
+    /// Resolve a global variable.
+    fn resolve_global(&self, field_name: &str, _global_type: &GlobalDescriptor) -> Result<GlobalRef, Error> {
+        self.deny(field_name)
+    }
+
+    /// Resolve a memory.
+    fn resolve_memory(&self, field_name: &str, _memory_type: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+        self.deny(field_name)
+    }
+
+    /// Resolve a table.
+    fn resolve_table(&self, field_name: &str, _table_type: &TableDescriptor) -> Result<TableRef, Error> {
+        self.deny(field_name)
+    }
+}
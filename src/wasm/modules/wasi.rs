@@ -25,34 +25,47 @@ use wasmi::{
     Signature, TableDescriptor, TableRef,
 };
 
+use crate::audit::{self, AuditEvent};
 use crate::prelude::*;
+use crate::wasm::SipId;
 
-pub struct WasiImportResolver;
+pub struct WasiImportResolver {
+    sip_id: SipId,
+}
 
 impl WasiImportResolver {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(sip_id: SipId) -> Self {
+        Self { sip_id }
+    }
+
+    fn deny<T>(&self, field_name: &str) -> Result<T, Error> {
+        audit::record(
+            self.sip_id,
+            AuditEvent::CapabilityDenied,
+            &format!("wasi_snapshot_preview1.{}", field_name),
+        );
+        Err(Error::Instantiation(format!("Export {} not found", field_name)))
     }
 }
 
 impl ModuleImportResolver for WasiImportResolver {
     /// Resolve a function.
     fn resolve_func(&self, field_name: &str, _signature: &Signature) -> Result<FuncRef, Error> {
-        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+        self.deny(field_name)
     }
 
     /// Resolve a global variable.
     fn resolve_global(&self, field_name: &str, _global_type: &GlobalDescriptor) -> Result<GlobalRef, Error> {
-        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+        self.deny(field_name)
     }
 
     /// Resolve a memory.
     fn resolve_memory(&self, field_name: &str, _memory_type: &MemoryDescriptor) -> Result<MemoryRef, Error> {
-        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+        self.deny(field_name)
     }
 
     /// Resolve a table.
     fn resolve_table(&self, field_name: &str, _table_type: &TableDescriptor) -> Result<TableRef, Error> {
-        Err(Error::Instantiation(format!("Export {} not found", field_name)))
+        self.deny(field_name)
     }
 }
@@ -0,0 +1,302 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use bitflags::bitflags;
+use chrono::Duration;
+use futures::future::{poll_fn, select};
+use futures::task::AtomicWaker;
+use spin::{Lazy, Mutex};
+use wasmi::HostError;
+
+use super::SipId;
+use crate::audit;
+use crate::memory::accounting::{self, Tag};
+use crate::memory::oom;
+use crate::prelude::*;
+use crate::tasks::park::sleep;
+
+static SIPS: Lazy<Mutex<BTreeMap<SipId, Arc<SipControl>>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+bitflags! {
+    /// Signals that can be delivered to a SIP
+    pub struct Signals: u32 {
+        /// The SIP should clean up and exit before its grace period expires
+        const TERMINATE = 1 << 0;
+    }
+}
+
+/// Error returned by a SIP that was force-killed by the kernel
+#[derive(Debug)]
+pub struct Killed;
+
+impl fmt::Display for Killed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SIP was killed")
+    }
+}
+
+impl HostError for Killed {}
+
+/// Shared state between a running SIP and the rest of the kernel
+pub struct SipControl {
+    pending: AtomicU32,
+    grace: Mutex<Option<Duration>>,
+    killed: AtomicBool,
+    /// Task of the program, waiting in a blocking host call
+    signal_waker: AtomicWaker,
+    /// Task of the watchdog, waiting for termination requests and kills
+    kill_waker: AtomicWaker,
+}
+
+impl SipControl {
+    fn new() -> Self {
+        Self {
+            pending: AtomicU32::new(0),
+            grace: Mutex::new(None),
+            killed: AtomicBool::new(false),
+            signal_waker: AtomicWaker::new(),
+            kill_waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Mark `signals` as pending and wake the SIP so it can notice them
+    pub fn raise(&self, signals: Signals) {
+        self.pending.fetch_or(signals.bits(), Ordering::AcqRel);
+        self.signal_waker.wake();
+    }
+
+    /// Take all pending signals
+    pub fn take_signals(&self) -> Signals {
+        Signals::from_bits_truncate(self.pending.swap(0, Ordering::AcqRel))
+    }
+
+    /// Resolves once there is any pending signal
+    pub fn poll_signals(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.signal_waker.register(cx.waker());
+
+        if self.pending.load(Ordering::Acquire) != 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn request_termination(&self, grace: Duration) {
+        // Later requests can't extend the first grace period
+        self.grace.lock().get_or_insert(grace);
+        self.kill_waker.wake();
+        self.raise(Signals::TERMINATE);
+    }
+
+    fn kill(&self) {
+        self.killed.store(true, Ordering::Release);
+        self.kill_waker.wake();
+    }
+
+    /// Whether the SIP was force-killed. Checked by the program whenever it
+    /// yields, so it stops even if the watchdog isn't polled first.
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    fn poll_kill(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.kill_waker.register(cx.waker());
+
+        if self.is_killed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Resolves with the grace period once termination was requested, or with
+    /// `None` if the SIP must be killed right away.
+    fn poll_termination(&self, cx: &mut Context<'_>) -> Poll<Option<Duration>> {
+        if self.poll_kill(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+
+        match *self.grace.lock() {
+            Some(grace) => Poll::Ready(Some(grace)),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Resolves when the SIP must be force-killed
+    pub async fn watchdog(&self) {
+        if let Some(grace) = poll_fn(|cx| self.poll_termination(cx)).await {
+            let kill = poll_fn(|cx| self.poll_kill(cx));
+            select(Box::pin(sleep(grace)), kill).await;
+        }
+    }
+}
+
+/// Registration of a running SIP. Dropping it removes the SIP from the SIP
+/// table, revokes its capabilities, stops accounting for it and refills the
+/// OOM reserve, however the SIP ended: it exited, was killed, or its future was
+/// dropped by a supervisor or an abort. It must be dropped after the instance,
+/// whose memory is charged to the SIP.
+pub struct SipHandle {
+    id: SipId,
+    control: Arc<SipControl>,
+}
+
+impl SipHandle {
+    pub fn register(id: SipId) -> Self {
+        let control = accounting::with_tag(Tag::Wasm, || {
+            let control = Arc::new(SipControl::new());
+            if SIPS.lock().insert(id, control.clone()).is_some() {
+                panic!("SIP with same ID already running");
+            }
+            control
+        });
+        Self { id, control }
+    }
+
+    pub fn id(&self) -> SipId {
+        self.id
+    }
+
+    pub fn control(&self) -> &Arc<SipControl> {
+        &self.control
+    }
+}
+
+impl Drop for SipHandle {
+    fn drop(&mut self) {
+        SIPS.lock().remove(&self.id);
+        audit::revoke_all(self.id);
+
+        let leaked = accounting::release(self.id);
+        if leaked > 0 {
+            warn!("SIP {:?} exited with {} bytes still allocated", self.id, leaked);
+        }
+        // In case it was killed for running out of memory
+        if !oom::replenish() {
+            warn!(
+                "No room for the out of memory reserve after SIP {:?} exited",
+                self.id
+            );
+        }
+    }
+}
+
+fn control_of(id: SipId) -> Option<Arc<SipControl>> {
+    SIPS.lock().get(&id).cloned()
+}
+
+/// List the currently running SIPs
+pub fn running() -> Vec<SipId> {
+    SIPS.lock().keys().copied().collect()
+}
+
+/// Ask a SIP to stop. It receives [`Signals::TERMINATE`] and is force-killed
+/// if it is still running after `grace`. Returns `false` if the SIP is not
+/// running.
+pub fn request_termination(id: SipId, grace: Duration) -> bool {
+    control_of(id)
+        .map(|control| control.request_termination(grace))
+        .is_some()
+}
+
+/// Force-kill a SIP, dropping its memory and host state at the next point it
+/// yields to the executor. Returns `false` if the SIP is not running.
+pub fn kill(id: SipId) -> bool {
+    control_of(id).map(|control| control.kill()).is_some()
+}
+
+/// Like [`kill`], giving up if the SIP table is locked. Used when the heap is
+/// exhausted, maybe by the code holding the lock.
+pub fn try_kill(id: SipId) -> bool {
+    let control = SIPS.try_lock().and_then(|sips| sips.get(&id).cloned());
+    control.map(|control| control.kill()).is_some()
+}
+
+/// Ask every running SIP to stop, used on shutdown
+pub fn terminate_all(grace: Duration) {
+    let sips: Vec<_> = SIPS.lock().values().cloned().collect();
+
+    for control in sips {
+        control.request_termination(grace);
+    }
+}
+
+#[test_case]
+fn test_signal_delivery() {
+    use futures::task::noop_waker_ref;
+
+    let control = SipControl::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+    assert!(control.poll_signals(&mut context).is_pending());
+
+    control.raise(Signals::TERMINATE);
+    assert!(control.poll_signals(&mut context).is_ready());
+    assert_eq!(control.take_signals(), Signals::TERMINATE);
+    assert!(control.take_signals().is_empty());
+}
+
+#[test_case]
+fn test_kill_during_grace_period() {
+    use core::future::Future;
+
+    use futures::task::noop_waker_ref;
+
+    let control = SipControl::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+    let mut watchdog = Box::pin(control.watchdog());
+    assert!(watchdog.as_mut().poll(&mut context).is_pending());
+
+    control.request_termination(Duration::seconds(60));
+    assert_eq!(control.take_signals(), Signals::TERMINATE);
+    assert!(watchdog.as_mut().poll(&mut context).is_pending());
+
+    control.kill();
+    assert!(control.is_killed());
+    assert!(watchdog.as_mut().poll(&mut context).is_ready());
+}
+
+#[test_case]
+fn test_grace_period_expires() {
+    use crate::tasks::executor::TaskExecutor;
+    use crate::tasks::park::now;
+
+    let control = Arc::new(SipControl::new());
+    control.request_termination(Duration::seconds(5));
+    // Later requests don't extend it
+    control.request_termination(Duration::seconds(60));
+
+    let mut executor = TaskExecutor::deterministic(0);
+    let watchdog = control.clone();
+    let waited = executor.block_on(async move {
+        let start = now();
+        watchdog.watchdog().await;
+        now() - start
+    });
+
+    assert!(waited >= Duration::seconds(5) && waited < Duration::seconds(60));
+}
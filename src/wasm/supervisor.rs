@@ -0,0 +1,377 @@
+// MIT License
+//
+// Copyright (c) 2021 Miguel Peláez
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use chrono::Duration;
+use futures::future::poll_fn;
+use wasmi::Error;
+
+use super::run_program;
+use crate::prelude::*;
+use crate::tasks::park::{now, sleep};
+
+type ChildFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ChildFailure>> + 'a>>;
+
+/// Which children are restarted when one of them fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Only the failed child is restarted
+    OneForOne,
+    /// All children are killed and restarted together
+    OneForAll,
+}
+
+/// When a child is restarted, like the restart types of Erlang/OTP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Always restarted, also after exiting normally
+    Permanent,
+    /// Restarted only after failing, or to restart it along with a failed
+    /// sibling while it still runs
+    Transient,
+    /// Never restarted, its failures don't affect its siblings either
+    Temporary,
+}
+
+impl Restart {
+    fn after_exit(self, failed: bool) -> bool {
+        match self {
+            Restart::Permanent => true,
+            Restart::Transient => failed,
+            Restart::Temporary => false,
+        }
+    }
+
+    /// Whether a sibling is restarted along with a failed child, `stopped` if
+    /// it already exited on its own
+    fn with_sibling(self, stopped: bool) -> bool {
+        match self {
+            Restart::Permanent => true,
+            Restart::Transient => !stopped,
+            Restart::Temporary => false,
+        }
+    }
+}
+
+/// How often a supervisor may restart its children before giving up
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Maximum number of restarts allowed within `period`
+    pub max_restarts: usize,
+    pub period: Duration,
+    /// Delay before the first restart, doubled for every recent restart
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            period: Duration::seconds(5),
+            initial_backoff: Duration::milliseconds(10),
+            max_backoff: Duration::seconds(1),
+        }
+    }
+}
+
+/// Error returned by a supervisor that exceeded its restart intensity, which
+/// its parent handles like any other failed child
+#[derive(Debug)]
+pub struct Escalation {
+    pub supervisor: String,
+}
+
+#[derive(Debug)]
+pub enum ChildFailure {
+    Trapped(Error),
+    Escalated(Escalation),
+}
+
+enum ChildKind {
+    Program { name: String, binary: Arc<[u8]> },
+    Supervisor(Supervisor),
+}
+
+struct ChildSpec {
+    kind: ChildKind,
+    restart: Restart,
+}
+
+impl ChildSpec {
+    fn name(&self) -> &str {
+        match &self.kind {
+            ChildKind::Program { name, .. } => name,
+            ChildKind::Supervisor(supervisor) => &supervisor.name,
+        }
+    }
+
+    fn start(&self) -> ChildFuture<'_> {
+        match &self.kind {
+            ChildKind::Program { binary, .. } => {
+                Box::pin(async move { run_program(binary).await.map_err(ChildFailure::Trapped) })
+            },
+            ChildKind::Supervisor(supervisor) => {
+                Box::pin(async move { supervisor.run().await.map_err(ChildFailure::Escalated) })
+            },
+        }
+    }
+}
+
+enum Slot<'a> {
+    Running(ChildFuture<'a>),
+    Backoff(Pin<Box<dyn Future<Output = ()> + 'a>>),
+    Stopped,
+}
+
+/// Runs SIPs and restarts them according to a [`Strategy`] and their
+/// [`Restart`] type when they trap or exit.
+pub struct Supervisor {
+    name: String,
+    strategy: Strategy,
+    policy: RestartPolicy,
+    children: Vec<ChildSpec>,
+}
+
+impl Supervisor {
+    pub fn new(name: &str, strategy: Strategy, policy: RestartPolicy) -> Self {
+        Self {
+            name: name.to_string(),
+            strategy,
+            policy,
+            children: Vec::new(),
+        }
+    }
+
+    /// Supervise a Webassembly program
+    pub fn with_program(mut self, name: &str, binary: Arc<[u8]>, restart: Restart) -> Self {
+        let kind = ChildKind::Program {
+            name: name.to_string(),
+            binary,
+        };
+        self.children.push(ChildSpec { kind, restart });
+        self
+    }
+
+    /// Supervise a nested supervisor, which escalates to this one
+    pub fn with_supervisor(mut self, supervisor: Supervisor, restart: Restart) -> Self {
+        let kind = ChildKind::Supervisor(supervisor);
+        self.children.push(ChildSpec { kind, restart });
+        self
+    }
+
+    /// Run all children until they exit, or until the restart intensity is
+    /// exceeded and the failure must be escalated
+    pub fn run(&self) -> Pin<Box<dyn Future<Output = Result<(), Escalation>> + '_>> {
+        Box::pin(self.supervise())
+    }
+
+    async fn supervise(&self) -> Result<(), Escalation> {
+        let mut slots: Vec<Slot> = self
+            .children
+            .iter()
+            .map(|child| Slot::Running(child.start()))
+            .collect();
+        let mut restarts = VecDeque::new();
+
+        while slots.iter().any(|slot| !matches!(slot, Slot::Stopped)) {
+            let (index, result) = poll_fn(|cx| self.poll_children(&mut slots, cx)).await;
+            let child = &self.children[index];
+
+            match &result {
+                Ok(()) => info!("Supervisor {}: {} exited", self.name, child.name()),
+                Err(failure) => warn!("Supervisor {}: {} failed: {:?}", self.name, child.name(), failure),
+            }
+
+            let restarted = self.restarted_children(&slots, index, result.is_err());
+            if restarted.is_empty() {
+                continue;
+            }
+            let restart_count = match record_restart(&mut restarts, now(), &self.policy) {
+                Some(restart_count) => restart_count,
+                None => {
+                    error!("Supervisor {}: restart intensity exceeded", self.name);
+                    return Err(Escalation {
+                        supervisor: self.name.clone(),
+                    });
+                },
+            };
+            let backoff = backoff_for(restart_count, &self.policy);
+
+            match self.strategy {
+                Strategy::OneForOne => slots[index] = Slot::Backoff(Box::pin(sleep(backoff))),
+                Strategy::OneForAll => {
+                    // Dropping the running siblings kills them
+                    for (index, slot) in slots.iter_mut().enumerate() {
+                        *slot = if restarted.contains(&index) {
+                            Slot::Backoff(Box::pin(sleep(backoff)))
+                        } else {
+                            Slot::Stopped
+                        };
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Children to restart once the child at `index` stopped, by index
+    fn restarted_children(&self, slots: &[Slot], index: usize, failed: bool) -> Vec<usize> {
+        if !self.children[index].restart.after_exit(failed) {
+            return Vec::new();
+        }
+
+        match self.strategy {
+            Strategy::OneForOne => vec![index],
+            Strategy::OneForAll => (0..slots.len())
+                .filter(|&sibling| {
+                    let stopped = matches!(slots[sibling], Slot::Stopped);
+                    sibling == index || self.children[sibling].restart.with_sibling(stopped)
+                })
+                .collect(),
+        }
+    }
+
+    /// Poll every child, restarting the ones whose backoff expired. Resolves
+    /// with the first child that finishes.
+    fn poll_children<'a>(
+        &'a self, slots: &mut [Slot<'a>], cx: &mut Context<'_>,
+    ) -> Poll<(usize, Result<(), ChildFailure>)> {
+        for (index, (slot, child)) in slots.iter_mut().zip(&self.children).enumerate() {
+            if let Slot::Backoff(backoff) = slot {
+                if backoff.as_mut().poll(cx).is_ready() {
+                    info!("Supervisor {}: restarting {}", self.name, child.name());
+                    *slot = Slot::Running(child.start());
+                }
+            }
+
+            if let Slot::Running(future) = slot {
+                if let Poll::Ready(result) = future.as_mut().poll(cx) {
+                    *slot = Slot::Stopped;
+                    return Poll::Ready((index, result));
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Record a restart at `now`, forgetting the ones older than the policy period.
+/// Returns how many restarts happened within the period, or `None` if there
+/// were too many.
+fn record_restart(restarts: &mut VecDeque<Duration>, now: Duration, policy: &RestartPolicy) -> Option<usize> {
+    while restarts.front().map_or(false, |time| now - *time > policy.period) {
+        restarts.pop_front();
+    }
+
+    if restarts.len() >= policy.max_restarts {
+        return None;
+    }
+    restarts.push_back(now);
+    Some(restarts.len())
+}
+
+fn backoff_for(restart_count: usize, policy: &RestartPolicy) -> Duration {
+    let exponent = min(restart_count.saturating_sub(1), 16) as u32;
+    min(policy.initial_backoff * 2i32.pow(exponent), policy.max_backoff)
+}
+
+#[test_case]
+fn test_restart_intensity() {
+    let policy = RestartPolicy::default();
+    let mut restarts = VecDeque::new();
+    let now = now();
+
+    for count in 1..=policy.max_restarts {
+        assert_eq!(record_restart(&mut restarts, now, &policy), Some(count));
+    }
+    assert_eq!(record_restart(&mut restarts, now, &policy), None);
+
+    let later = now + policy.period + Duration::seconds(1);
+    assert_eq!(record_restart(&mut restarts, later, &policy), Some(1));
+}
+
+#[test_case]
+fn test_one_for_all_restarts() {
+    use futures::future::pending;
+
+    let binary: Arc<[u8]> = Arc::from(&[][..]);
+    let supervisor = Supervisor::new("test", Strategy::OneForAll, RestartPolicy::default())
+        .with_program("failed", binary.clone(), Restart::Transient)
+        .with_program("running", binary.clone(), Restart::Transient)
+        .with_program("exited", binary.clone(), Restart::Transient)
+        .with_program("permanent", binary.clone(), Restart::Permanent)
+        .with_program("temporary", binary, Restart::Temporary);
+    let running = || Slot::Running(Box::pin(pending::<Result<(), ChildFailure>>()));
+    let slots = [Slot::Stopped, running(), Slot::Stopped, Slot::Stopped, running()];
+
+    // Siblings that exited normally stay down unless they are permanent
+    assert_eq!(supervisor.restarted_children(&slots, 0, true), vec![0, 1, 3]);
+    assert!(supervisor.restarted_children(&slots, 0, false).is_empty());
+    // A temporary child never restarts anything
+    assert!(supervisor.restarted_children(&slots, 4, true).is_empty());
+}
+
+#[test_case]
+fn test_one_for_one_restarts() {
+    let binary: Arc<[u8]> = Arc::from(&[][..]);
+    let supervisor = Supervisor::new("test", Strategy::OneForOne, RestartPolicy::default())
+        .with_program("transient", binary.clone(), Restart::Transient)
+        .with_program("permanent", binary, Restart::Permanent);
+    let slots = [Slot::Stopped, Slot::Stopped];
+
+    assert_eq!(supervisor.restarted_children(&slots, 0, true), vec![0]);
+    assert!(supervisor.restarted_children(&slots, 0, false).is_empty());
+    assert_eq!(supervisor.restarted_children(&slots, 1, false), vec![1]);
+}
+
+#[test_case]
+fn test_escalation() {
+    use crate::tasks::executor::TaskExecutor;
+
+    // Not a valid module, it fails every time it starts
+    let binary: Arc<[u8]> = Arc::from(&[0u8; 4][..]);
+    let supervisor = Supervisor::new("test", Strategy::OneForOne, RestartPolicy::default()).with_program(
+        "broken",
+        binary,
+        Restart::Permanent,
+    );
+
+    let mut executor = TaskExecutor::deterministic(0);
+    let result = executor.block_on(async move { supervisor.run().await.map_err(|error| error.supervisor) });
+    assert_eq!(result, Err("test".to_string()));
+}
+
+#[test_case]
+fn test_restart_backoff() {
+    let policy = RestartPolicy::default();
+
+    assert_eq!(backoff_for(1, &policy), policy.initial_backoff);
+    assert_eq!(backoff_for(2, &policy), policy.initial_backoff * 2);
+    assert_eq!(backoff_for(64, &policy), policy.max_backoff);
+}